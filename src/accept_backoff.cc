@@ -0,0 +1,28 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <unistd.h>
+#include <algorithm>
+#include <string>
+#include "main.h"
+
+void AcceptBackoff::failed(const boost::system::error_code& error) {
+  if (0 == delay_ms)
+    log(LogLevel::WARN, "couldn't accept a connection on " + what + ": " +
+                            error.message() + "; backing off");
+  delay_ms = std::min(1000u, 0 == delay_ms ? 10u : 2 * delay_ms);
+  usleep(delay_ms * 1000);
+}
@@ -0,0 +1,131 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+
+#include <algorithm>
+#include <boost/asio.hpp>
+#include <iostream>
+#include <iterator>
+#include <memory>
+#include <mutex>
+#include <regex>
+#include <sstream>
+#include <string>
+#include "main.h"
+
+using boost::asio::local::stream_protocol;
+using std::back_inserter;
+using std::regex;
+using std::regex_match;
+using std::string;
+using std::transform;
+
+namespace {
+// As with the loaded hashes, lookups work from a snapshot, and
+// overlay_mutex only guards the pointer.
+std::shared_ptr<const HashOverlay> overlay{std::make_shared<HashOverlay>()};
+std::mutex overlay_mutex;
+
+/** Swaps in a copy of the overlay with one hash added or removed.
+ * @return false if the overlay already said the same about it
+ */
+bool change_overlay(const string& hash, const bool add) {
+  std::lock_guard<std::mutex> lock(overlay_mutex);
+  auto fresh = std::make_shared<HashOverlay>(*overlay);
+  auto& into = add ? fresh->added : fresh->removed;
+  auto& outof = add ? fresh->removed : fresh->added;
+  const auto changed = into.insert(hash).second;
+  outof.erase(hash);
+  overlay = std::move(fresh);
+  return changed;
+}
+
+string uppercase(const string& text) {
+  string rv;
+  transform(text.cbegin(), text.cend(), back_inserter(rv), ::toupper);
+  return rv;
+}
+
+/** Answers one admin command.
+ * @return the reply, including its line ending; empty for BYE
+ */
+string answer(const string& line) {
+  static const regex hash_re{"^([0-9A-F]{32}|[0-9A-F]{40}|[0-9A-F]{64})$"};
+  std::istringstream words{line};
+  string command, hash, extra;
+  words >> command >> hash >> extra;
+  command = uppercase(command);
+  hash = uppercase(hash);
+
+  if ("BYE" == command) return "";
+  if ("ADD" != command && "DEL" != command)
+    return "NOT OK unknown command\r\n";
+  if (hash.empty()) return "NOT OK missing argument\r\n";
+  if (!extra.empty() || !regex_match(hash, hash_re))
+    return "NOT OK bad hash\r\n";
+
+  const auto add = "ADD" == command;
+  if (change_overlay(hash, add))
+    log(LogLevel::INFO, string("admin socket ") +
+                            (add ? "added " : "removed ") + hash);
+  return "OK\r\n";
+}
+}  // namespace
+
+bool HashOverlay::empty() const { return added.empty() && removed.empty(); }
+
+/** Whether hash should be reported present, given that the loaded
+ * hashes say found.
+ */
+bool HashOverlay::apply(const string& hash, const bool found) const {
+  const auto key = uppercase(hash);
+  if (removed.count(key)) return false;
+  return found || 0 != added.count(key);
+}
+
+/** The runtime changes to apply on top of the loaded hashes. */
+std::shared_ptr<const HashOverlay> current_overlay() {
+  std::lock_guard<std::mutex> lock(overlay_mutex);
+  return overlay;
+}
+
+/** Answers ADD and DEL commands on acceptor, one connection at a time,
+ * for as long as the server runs.
+ */
+void serve_admin(boost::asio::io_service& io_service,
+                 stream_protocol::acceptor& acceptor) {
+  AcceptBackoff backoff{"the admin socket"};
+  for (;;) {
+    stream_protocol::socket socket(io_service);
+    boost::system::error_code error;
+    acceptor.accept(socket, error);
+    if (error) {
+      backoff.failed(error);
+      continue;
+    }
+    backoff.succeeded();
+
+    PlainSocketBuffer buffer(socket.native_handle());
+    std::iostream stream(&buffer);
+    string line;
+    while (getline(stream, line)) {
+      const auto reply = answer(line);
+      if (reply.empty()) break;
+      stream << reply << std::flush;
+    }
+    socket.close(error);
+  }
+}
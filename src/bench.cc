@@ -0,0 +1,100 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <algorithm>
+#include <chrono>
+#include <cstddef>
+#include <iomanip>
+#include <iostream>
+#include <random>
+#include <sstream>
+#include <string>
+#include <vector>
+#include "main.h"
+
+using std::cout;
+using std::string;
+using std::vector;
+using std::chrono::duration;
+using std::chrono::steady_clock;
+
+namespace {
+/** As many hashes as a typical nsrllookup QUERY line carries. */
+const size_t batch_size{100};
+
+string random_md5(std::mt19937_64& rng) {
+  return from_pair64(pair64{rng(), rng()});
+}
+
+void row(const string& label, const string& value) {
+  cout << std::left << std::setw(20) << label << value << "\n";
+}
+}  // namespace
+
+/** Times count lookups through the same path QUERY uses, then prints a
+ * summary.  Every other query is one of present, which should hold
+ * hashes known to be loaded; the rest are random MD5s, which nearly
+ * always miss.
+ * @param load_seconds how long loading took, for the summary
+ */
+void run_benchmark(const vector<string>& present, const uint64_t count,
+                   const double load_seconds) {
+  std::mt19937_64 rng{std::random_device{}()};
+  vector<string> queries;
+  queries.reserve(count);
+  uint64_t known{0};
+  for (uint64_t idx = 0; idx < count; ++idx) {
+    if (!present.empty() && 0 == idx % 2) {
+      queries.push_back(present[idx / 2 % present.size()]);
+      ++known;
+    } else {
+      queries.push_back(random_md5(rng));
+    }
+  }
+
+  uint64_t hits{0};
+  const auto started = steady_clock::now();
+  for (auto first = queries.cbegin(); first != queries.cend();) {
+    const auto last =
+        first + std::min<std::ptrdiff_t>(batch_size, queries.cend() - first);
+    const auto results = lookup_hashes(first, last);
+    hits += static_cast<uint64_t>(
+        std::count(results.cbegin(), results.cend(), true));
+    first = last;
+  }
+  const duration<double> elapsed = steady_clock::now() - started;
+
+  std::ostringstream load_time;
+  load_time << std::fixed << std::setprecision(2) << load_seconds << " s";
+  std::ostringstream memory;
+  const auto rss = resident_bytes();
+  if (0 < rss)
+    memory << std::fixed << std::setprecision(1) << rss / 1048576.0 << " MiB";
+  else
+    memory << "unknown";
+  std::ostringstream rate;
+  rate << std::fixed << std::setprecision(0)
+       << (0 < elapsed.count() ? count / elapsed.count() : 0.0)
+       << " queries/s";
+
+  row("hashes loaded", std::to_string(loaded_hash_count()));
+  row("load time", load_time.str());
+  row("resident memory", memory.str());
+  row("queries", std::to_string(count) + " (" + std::to_string(known) +
+                     " known present)");
+  row("hits", std::to_string(hits));
+  row("lookup rate", rate.str());
+}
@@ -0,0 +1,55 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <cmath>
+#include "engine.h"
+
+namespace {
+const double ln2{std::log(2.0)};
+}
+
+BloomFilter::BloomFilter(const uint64_t expected, const double fpr) {
+  // The textbook sizing: m = -n ln(p) / (ln 2)^2 bits, with k = (m/n) ln 2
+  // probes per element.  A filter for nothing still gets one word so
+  // the modulus below is never zero.
+  const auto n = static_cast<double>(std::max<uint64_t>(expected, 1));
+  const auto m = std::ceil(-n * std::log(fpr) / (ln2 * ln2));
+  bits = std::max<uint64_t>(static_cast<uint64_t>(m), 64);
+  probes = std::max<uint32_t>(
+      static_cast<uint32_t>(std::round((bits / n) * ln2)), 1);
+  words.assign((bits + 63) / 64, 0);
+}
+
+// The values going into the filter are cryptographic hashes, so their
+// bits are already uniformly distributed: there's no need to hash them
+// again.  The two halves of the key drive standard double hashing.
+void BloomFilter::insert(const pair64& key) {
+  const auto stride = key.second | 1;
+  for (uint32_t idx = 0; idx < probes; ++idx) {
+    const auto bit = (key.first + idx * stride) % bits;
+    words[bit / 64] |= (1ULL << (bit % 64));
+  }
+  count += 1;
+}
+
+bool BloomFilter::contains(const pair64& key) const {
+  const auto stride = key.second | 1;
+  for (uint32_t idx = 0; idx < probes; ++idx) {
+    const auto bit = (key.first + idx * stride) % bits;
+    if (0 == (words[bit / 64] & (1ULL << (bit % 64)))) return false;
+  }
+  return true;
+}
@@ -0,0 +1,160 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <boost/asio.hpp>
+#include <chrono>
+#include <cstdlib>
+#include <fstream>
+#include <iostream>
+#include <sstream>
+#include <string>
+#include <thread>
+#include <vector>
+#include "main.h"
+
+using boost::asio::ip::tcp;
+using std::cerr;
+using std::cout;
+using std::string;
+using std::vector;
+
+namespace {
+/** Hashes per QUERY line, the same as nsrllookup sends. */
+const size_t batch_size{100};
+
+/** How many times a rate-limited query is retried before giving up. */
+const int rate_limit_retries{30};
+
+bool read_reply(std::istream& server, string& reply) {
+  if (!getline(server, reply)) return false;
+  if (!reply.empty() && '\r' == reply.back()) reply.pop_back();
+  return true;
+}
+
+/** Where the answers go, and how many there have been. */
+struct Results {
+  std::ostream& out;
+  bool csv;
+  uint64_t hashes{0};
+  uint64_t hits{0};
+};
+
+/** Sends one QUERY and prints each hash in it with its answer.  A
+ * rate-limited query is retried once a second.
+ * @return false if the server hung up or gave an answer that made no
+ * sense
+ */
+bool query(tcp::iostream& server, const vector<string>& batch,
+           Results& results) {
+  const auto request = encode_query(batch);
+  string reply;
+  for (int attempt = 0; attempt <= rate_limit_retries; ++attempt) {
+    server << request << std::flush;
+    if (!read_reply(server, reply)) {
+      cerr << "The server hung up mid-query.\n";
+      return false;
+    }
+    if ("RATE LIMITED" != reply) break;
+    std::this_thread::sleep_for(std::chrono::seconds(1));
+  }
+
+  vector<bool> found;
+  if (!parse_query_response(reply, batch.size(), found)) {
+    cerr << "The server answered a query with \"" << reply << "\".\n";
+    return false;
+  }
+  for (size_t idx = 0; idx < batch.size(); ++idx) {
+    if (results.csv)
+      results.out << batch[idx] << "," << (found[idx] ? "true" : "false")
+                  << "\n";
+    else
+      results.out << batch[idx] << " " << (found[idx] ? "present" : "absent")
+                  << "\n";
+    results.hits += found[idx] ? 1 : 0;
+  }
+  results.hashes += batch.size();
+  return true;
+}
+}  // namespace
+
+/** Runs nsrlsvr as a client of another nsrlsvr: reads hashes,
+ * separated by whitespace, and prints each with whether the server at
+ * host and port knows it.  Anything from a # to the end of its line is
+ * a comment.
+ * @param input where to read hashes from; standard input if empty
+ * @param output where to write them to as CSV; standard output, in
+ * plain text, if empty
+ * @return the exit status for the process
+ */
+int run_check(const string& host, const uint16_t port, const string& input,
+              const string& output) {
+  std::ifstream infile;
+  if (!input.empty()) {
+    infile.open(input);
+    if (!infile) {
+      cerr << "Couldn't open " << input << " for reading.\n";
+      return EXIT_FAILURE;
+    }
+  }
+  std::ofstream outfile;
+  if (!output.empty()) {
+    outfile.open(output);
+    if (!outfile) {
+      cerr << "Couldn't open " << output << " for writing.\n";
+      return EXIT_FAILURE;
+    }
+    outfile << "hash,present\n";
+  }
+  std::istream& in = input.empty() ? std::cin : infile;
+  Results results{output.empty() ? cout : outfile, !output.empty()};
+
+  tcp::iostream server(host, std::to_string(port));
+  if (!server) {
+    cerr << "Couldn't connect to " << host << " port " << port << ": "
+         << server.error().message() << "\n";
+    return EXIT_FAILURE;
+  }
+
+  string reply;
+  server << encode_version("2.0") << std::flush;
+  if (!read_reply(server, reply) || "OK" != reply) {
+    cerr << host << " doesn't speak protocol version 2.0.\n";
+    return EXIT_FAILURE;
+  }
+
+  vector<string> batch;
+  string line;
+  while (getline(in, line)) {
+    std::istringstream words{line.substr(0, line.find('#'))};
+    string hash;
+    while (words >> hash) {
+      batch.push_back(hash);
+      if (batch.size() < batch_size) continue;
+      if (!query(server, batch, results)) return EXIT_FAILURE;
+      batch.clear();
+    }
+  }
+  if (!batch.empty() && !query(server, batch, results)) return EXIT_FAILURE;
+
+  server << encode_bye() << std::flush;
+  if (!output.empty() && !(outfile << std::flush)) {
+    cerr << "Couldn't write to " << output << ".\n";
+    return EXIT_FAILURE;
+  }
+  cerr << results.hashes << " hashes, " << results.hits << " present, "
+       << results.hashes - results.hits << " absent\n";
+  return EXIT_SUCCESS;
+}
@@ -0,0 +1,86 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+
+#include <stdexcept>
+#include "engine.h"
+
+// MD5s are uniformly distributed, so the first few bits of a sorted run
+// of them are close to a count: the top bucket_bits bits of each hash
+// pick a bucket, starts says where each bucket begins, and only the
+// remaining bits are stored.  Buckets average eight to sixteen hashes.
+
+CompactSet::CompactSet(const std::vector<pair64>& sorted)
+    : count{sorted.size()} {
+  if (count >= (1ULL << 32))
+    throw std::length_error("too many hashes for a compact set");
+  while (bucket_bits < 29 && (count >> (bucket_bits + 4)) > 0) ++bucket_bits;
+  rest_bits = 64 - bucket_bits;
+
+  starts.assign((1ULL << bucket_bits) + 1, 0);
+  rests.assign((count * rest_bits + 63) / 64 + 1, 0);
+  lows.reserve(count);
+  for (std::size_t idx = 0; idx < count; ++idx) {
+    const auto& key = sorted[idx];
+    starts[bucket(key) + 1] += 1;
+    const auto bit = idx * rest_bits;
+    const auto offset = bit % 64;
+    rests[bit / 64] |= rest(key) << offset;
+    if (offset + rest_bits > 64)
+      rests[bit / 64 + 1] |= rest(key) >> (64 - offset);
+    lows.push_back(key.second);
+  }
+  for (std::size_t idx = 1; idx < starts.size(); ++idx)
+    starts[idx] += starts[idx - 1];
+}
+
+bool CompactSet::contains(const pair64& key) const {
+  const auto wanted = rest(key);
+  auto first = static_cast<std::size_t>(starts[bucket(key)]);
+  auto last = static_cast<std::size_t>(starts[bucket(key) + 1]);
+  while (first < last) {
+    const auto middle = first + (last - first) / 2;
+    const auto found = stored_rest(middle);
+    if (found == wanted && lows[middle] == key.second) return true;
+    if (found < wanted || (found == wanted && lows[middle] < key.second))
+      first = middle + 1;
+    else
+      last = middle;
+  }
+  return false;
+}
+
+uint64_t CompactSet::bytes() const {
+  return starts.size() * sizeof(uint32_t) +
+         (rests.size() + lows.size()) * sizeof(uint64_t);
+}
+
+uint64_t CompactSet::bucket(const pair64& key) const {
+  return 0 == bucket_bits ? 0 : key.first >> rest_bits;
+}
+
+uint64_t CompactSet::rest(const pair64& key) const {
+  return 64 == rest_bits ? key.first
+                         : key.first & ((1ULL << rest_bits) - 1);
+}
+
+uint64_t CompactSet::stored_rest(const std::size_t idx) const {
+  const auto bit = idx * rest_bits;
+  const auto offset = bit % 64;
+  auto value = rests[bit / 64] >> offset;
+  if (offset + rest_bits > 64) value |= rests[bit / 64 + 1] << (64 - offset);
+  return 64 == rest_bits ? value : value & ((1ULL << rest_bits) - 1);
+}
@@ -0,0 +1,262 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#ifndef ENGINE_H
+#define ENGINE_H
+
+// What nsrlsvr and the nsrl library share behind nsrl.h: how hashes
+// are stored, loaded and looked up.  Like nsrl.h, it needs nothing
+// from Boost, so the library can be built without it.
+
+#include <array>
+#include <cstdint>
+#include <memory>
+#include <streambuf>
+#include <string>
+#include <utility>
+#include <vector>
+#include "nsrl.h"
+
+// Note: C++11 guarantees an unsigned long long will be at least 64 bits.
+// A compile-time assert in main.cc guarantees it will ONLY be 64 bits.
+using pair64 = std::pair<unsigned long long, unsigned long long>;
+
+// A SHA-1 doesn't split evenly into 64-bit words, so it's kept as raw
+// bytes, and SHA-256 is stored the same way for consistency.
+// std::array already supplies the ordering sort() needs.
+using sha1_digest = std::array<unsigned char, 20>;
+using sha256_digest = std::array<unsigned char, 32>;
+
+/** A read-only view of a sorted run of hashes, whether they live in a
+ * vector on the heap or in a memory-mapped index.
+ */
+template <typename T>
+class HashView {
+ public:
+  HashView() = default;
+  HashView(const T* first, const T* last) : head{first}, tail{last} {}
+  const T* cbegin() const { return head; }
+  const T* cend() const { return tail; }
+  std::size_t size() const { return static_cast<std::size_t>(tail - head); }
+
+ private:
+  const T* head{nullptr};
+  const T* tail{nullptr};
+};
+
+/** A Bloom filter over hash values, for hosts that can't afford to
+ * keep the full sorted hash set in RAM.  It never reports a false
+ * negative; false positives occur at roughly the rate it was sized for.
+ */
+class BloomFilter {
+ public:
+  BloomFilter(const uint64_t expected, const double fpr);
+  void insert(const pair64& key);
+  bool contains(const pair64& key) const;
+  uint64_t size() const { return count; }
+  uint64_t bytes() const { return words.size() * sizeof(uint64_t); }
+  uint32_t hash_functions() const { return probes; }
+
+ private:
+  uint64_t bits{0};
+  uint32_t probes{0};
+  uint64_t count{0};
+  std::vector<uint64_t> words;
+};
+
+/** A sorted set of MD5s in about 15% less memory than a plain array,
+ * for hosts where the full set doesn't quite fit.  MD5s are random
+ * enough that there's little more to squeeze out of them.  Lookups
+ * only search the hash's bucket, which tends to make them quicker
+ * than a binary search over the whole array despite the unpacking.
+ */
+class CompactSet {
+ public:
+  explicit CompactSet(const std::vector<pair64>& sorted);
+  bool contains(const pair64& key) const;
+  uint64_t size() const { return count; }
+  uint64_t bytes() const;
+
+ private:
+  uint64_t bucket(const pair64& key) const;
+  uint64_t rest(const pair64& key) const;
+  uint64_t stored_rest(const std::size_t idx) const;
+
+  uint64_t count{0};
+  unsigned bucket_bits{0};
+  unsigned rest_bits{64};
+  std::vector<uint32_t> starts;
+  /** What's left of each hash's first half, bit-packed. */
+  std::vector<uint64_t> rests;
+  std::vector<uint64_t> lows;
+};
+
+/** Where each shard of a sorted run of MD5s starts, the shards being
+ * keyed by the hashes' leading bits.  A lookup goes straight to its
+ * hash's shard and binary searches only that, which on big datasets
+ * takes fewer of the steps that miss the cache.
+ */
+class ShardTable {
+ public:
+  ShardTable(const HashView<pair64>& sorted, const unsigned bits);
+  /** @param sorted the same hashes the table was built over */
+  bool contains(const HashView<pair64>& sorted, const pair64& key) const;
+  uint64_t bytes() const { return starts.size() * sizeof(uint64_t); }
+
+ private:
+  uint64_t shard(const pair64& key) const;
+
+  unsigned shard_bits;
+  std::vector<uint64_t> starts;
+};
+
+/** Everything queries are answered from.  Once published it never
+ * changes: a reload builds a whole new one and swaps it in, and the
+ * old one is freed when the last lookup still using it finishes.
+ */
+struct HashData {
+  HashData() = default;
+  HashData(const HashData&) = delete;
+  HashData& operator=(const HashData&) = delete;
+  ~HashData();
+
+  /** The MD5s, pointing into md5_store or into a mapped index. */
+  HashView<pair64> md5s;
+  std::vector<pair64> md5_store;
+  std::vector<sha1_digest> sha1s;
+  std::vector<sha256_digest> sha256s;
+  /** If set, it holds the MD5s in md5s' place. */
+  std::unique_ptr<CompactSet> compact_md5s;
+  /** With --shard-bits, where each shard of md5s starts. */
+  std::unique_ptr<ShardTable> md5_shards;
+  /** If set, it stands in for all of the above.  With --bloom-verify
+   * it's in front of a mapped index instead, which checks its hits.
+   */
+  std::unique_ptr<BloomFilter> filter;
+  /** With --track-sources, which of sources each hash came from, in
+   * step with md5s, sha1s and sha256s.
+   */
+  std::vector<uint8_t> md5_sources;
+  std::vector<uint8_t> sha1_sources;
+  std::vector<uint8_t> sha256_sources;
+  std::vector<std::string> sources;
+  /** From --sha1-md5-map, for the main hash files only: SHA-1s sorted
+   * with the MD5 each stands for.
+   */
+  std::vector<std::pair<sha1_digest, pair64>> sha1_md5s;
+  /** The --index mapping md5s points into, if there is one. */
+  void* mapping{nullptr};
+  std::size_t mapping_length{0};
+
+  uint64_t size() const {
+    if (filter) return filter->size();
+    return md5s.size() + (compact_md5s ? compact_md5s->size() : 0) +
+           sha1s.size() + sha256s.size();
+  }
+};
+
+/** Everything loaded from a hash file. */
+struct HashSets {
+  std::vector<pair64> md5s;
+  std::vector<sha1_digest> sha1s;
+  std::vector<sha256_digest> sha256s;
+  // Only filled in with --track-sources.
+  std::vector<uint8_t> md5_sources;
+  std::vector<uint8_t> sha1_sources;
+  std::vector<uint8_t> sha256_sources;
+  std::vector<std::string> sources;
+
+  std::size_t size() const {
+    return md5s.size() + sha1s.size() + sha256s.size();
+  }
+};
+
+/** How load_hashes() reads hash files, which nsrlsvr takes from its
+ * options.
+ */
+struct LoadOptions {
+  bool rds_sha1{false};
+  uint64_t progress_interval{0};
+  bool track_sources{false};
+  /** Where progress is reported, if anywhere. */
+  void (*log)(const LogLevel, const std::string&){nullptr};
+};
+
+unsigned worker_count();
+[[noreturn]] void open_failed(const std::string& what);
+void load_hashes(HashSets& sets, const std::vector<std::string>& files,
+                 const LoadOptions& options);
+std::shared_ptr<HashData> make_hash_data(
+    HashSets& sets, std::unique_ptr<BloomFilter>& filter,
+    std::unique_ptr<CompactSet>& compact);
+bool contains_hash(const HashData& data, const std::string& hash);
+
+/** Reduces a digest to the 128-bit key a BloomFilter works on. */
+inline const pair64& bloom_key(const pair64& digest) { return digest; }
+
+template <std::size_t N>
+pair64 bloom_key(const std::array<unsigned char, N>& digest) {
+  static_assert(N >= 16, "digest too short for a Bloom filter key");
+  pair64 rv{0, 0};
+  for (std::size_t idx = 0; idx < 8; ++idx) {
+    rv.first = (rv.first << 8) | digest[idx];
+    rv.second = (rv.second << 8) | digest[idx + 8];
+  }
+  return rv;
+}
+
+#ifdef HAVE_ZLIB
+struct gzFile_s;
+
+/** A read-only streambuf that decompresses a gzip file as it goes.
+ * Decompression stops at the first error, which ends input just like
+ * the end of the file would; error() tells the two apart.
+ */
+class GzipBuffer : public std::streambuf {
+ public:
+  explicit GzipBuffer(const std::string& path);
+  GzipBuffer(const GzipBuffer&) = delete;
+  GzipBuffer& operator=(const GzipBuffer&) = delete;
+  ~GzipBuffer() override;
+
+  bool is_open() const { return nullptr != file; }
+  /** Why decompression stopped early, or empty if it didn't. */
+  const std::string& error() const { return failure; }
+
+ protected:
+  int_type underflow() override;
+
+ private:
+  gzFile_s* file;
+  const std::string path;
+  std::string failure;
+  std::array<char, 65536> input;
+};
+#endif
+
+pair64 to_pair64(const std::string&);
+std::string from_pair64(const pair64&);
+sha1_digest to_sha1(const std::string&);
+sha256_digest to_sha256(const std::string&);
+#ifdef HAVE_SQLITE3
+void load_rds_database(const std::string&, std::vector<pair64>&,
+                       std::vector<sha1_digest>&, std::vector<sha256_digest>&);
+#endif
+bool operator<(const pair64& lhs, const pair64& rhs);
+bool operator==(const pair64& lhs, const pair64& rhs);
+bool operator>(const pair64& lhs, const pair64& rhs);
+
+#endif
@@ -0,0 +1,65 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include "engine.h"
+
+#ifdef HAVE_ZLIB
+
+#include <errno.h>
+#include <zlib.h>
+#include <cstring>
+
+using std::string;
+
+/** Opens path for decompression.  On failure is_open() is false and
+ * errno says why.
+ */
+GzipBuffer::GzipBuffer(const string& path)
+    : file{gzopen(path.c_str(), "rb")}, path{path} {
+  // The default 8 KiB buffer makes for a lot of read() calls on a
+  // multi-gigabyte hash file.
+  if (nullptr != file) gzbuffer(file, 1 << 20);
+}
+
+GzipBuffer::~GzipBuffer() {
+  if (nullptr != file) gzclose(file);
+}
+
+GzipBuffer::int_type GzipBuffer::underflow() {
+  if (gptr() < egptr()) return traits_type::to_int_type(*gptr());
+  if (nullptr == file || !failure.empty()) return traits_type::eof();
+
+  const auto count =
+      gzread(file, input.data(), static_cast<unsigned>(input.size()));
+  if (0 < count) {
+    setg(input.data(), input.data(), input.data() + count);
+    return traits_type::to_int_type(*gptr());
+  }
+
+  // A truncated file reads as a clean end unless we ask.
+  int status{Z_OK};
+  const char* message = gzerror(file, &status);
+  if (0 > count || Z_OK != status) {
+    failure = Z_ERRNO == status ? std::strerror(errno) : message;
+    // zlib puts the path in front, but callers already name the file.
+    const auto prefix = path + ": ";
+    if (0 == failure.compare(0, prefix.size(), prefix))
+      failure.erase(0, prefix.size());
+  }
+  return traits_type::eof();
+}
+
+#endif
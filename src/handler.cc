@@ -15,21 +15,26 @@ OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
 */
 
 #include <algorithm>
+#include <bitset>
+#include <signal.h>
 #include <boost/tokenizer.hpp>
+#include <chrono>
 #include <exception>
+#include <functional>
 #include <iostream>
 #include <iterator>
+#include <memory>
 #include <sstream>
+#include <stdexcept>
 #include <vector>
 #include "main.h"
 
 using boost::char_separator;
 using boost::tokenizer;
-using boost::asio::ip::tcp;
 using std::back_inserter;
-using std::binary_search;
 using std::exception;
 using std::getline;
+using std::invalid_argument;
 using std::pair;
 using std::string;
 using std::stringstream;
@@ -37,9 +42,22 @@ using std::to_string;
 using std::transform;
 using std::vector;
 using std::distance;
+using std::chrono::duration_cast;
+using std::chrono::seconds;
+using std::chrono::steady_clock;
 
 // defined in main.cc
-extern const vector<pair64>& hashes;
+extern const steady_clock::time_point& start_time;
+extern const volatile sig_atomic_t& stop_requested;
+extern const std::atomic<unsigned int>& active_sessions;
+extern const std::unique_ptr<RateLimiter>& rate_limiter;
+extern const std::unique_ptr<QueryCache>& query_cache;
+extern const bool& invert_results;
+extern const bool& nearest_enabled;
+extern const char& hit_char;
+extern const char& miss_char;
+extern const unsigned int& max_query_hashes;
+extern const size_t& max_line_bytes;
 
 namespace {
 enum class Command {
@@ -49,9 +67,24 @@ enum class Command {
   Query = 3,
   Upshift = 4,
   Downshift = 5,
-  Unknown = 6
+  Format = 6,
+  QueryPrefix = 7,
+  Dataset = 8,
+  QuerySource = 9,
+  Nearest = 10,
+  Unknown = 11
 };
 
+// QUERYPREFIX wants at least this many hex digits, so that no prefix
+// matches more than a handful of hashes, and lists at most this many
+// of the hashes it matches.
+constexpr size_t min_prefix_digits{8};
+constexpr size_t max_prefix_listed{100};
+
+// NEAREST looks at most this many hex digits away, and lists as many of
+// the hashes it finds as QUERYPREFIX does.
+constexpr unsigned max_nearest_distance{3};
+
 auto tokenize(const string&& line) {
   vector<string> rv;
   char_separator<char> sep(" ");
@@ -62,8 +95,20 @@ auto tokenize(const string&& line) {
   return rv;
 }
 
-bool is_present_in_hashes(const string& hash) {
-  return binary_search(hashes.cbegin(), hashes.cend(), to_pair64(hash));
+bool is_present_in_hashes(const HashData& data, const string& hash) {
+  // A token that isn't a well-formed MD5, SHA-1 or SHA-256 can't be
+  // in the hash set.
+  // Report it as a miss instead of letting the exception tear down
+  // the client's whole session.
+  try {
+    return contains_hash(data, hash);
+  } catch (invalid_argument&) {
+    if (log_enabled(LogLevel::DEBUG))
+      log(LogLevel::DEBUG, "treating malformed hash " +
+                               json_string(hash.substr(0, 70)) +
+                               " as a miss");
+    return false;
+  }
 }
 
 auto getCommand(const string& cmdstring) {
@@ -85,66 +130,671 @@ auto getCommand(const string& cmdstring) {
     cmd = Command::Upshift;
   else if (localcmd == "DOWNSHIFT")
     cmd = Command::Downshift;
+  else if (localcmd == "FORMAT:")
+    cmd = Command::Format;
+  else if (localcmd == "QUERYPREFIX")
+    cmd = Command::QueryPrefix;
+  else if (localcmd == "DATASET")
+    cmd = Command::Dataset;
+  else if (localcmd == "QUERYSOURCE")
+    cmd = Command::QuerySource;
+  else if (localcmd == "NEAREST")
+    cmd = Command::Nearest;
 
   return cmd;
 }
+
+/** Whether a command is part of protocol 1.x.  STATUS, UPSHIFT,
+ * DOWNSHIFT and FORMAT: came with 2.0, so a session that agreed on 1.x
+ * doesn't get them.
+ */
+bool is_protocol1_command(const Command command) {
+  switch (command) {
+    case Command::Version:
+    case Command::Bye:
+    case Command::Query:
+    case Command::Unknown:
+      return true;
+    case Command::Status:
+    case Command::Upshift:
+    case Command::Downshift:
+    case Command::Format:
+    case Command::QueryPrefix:
+    case Command::Dataset:
+    case Command::QuerySource:
+    case Command::Nearest:
+      break;
+  }
+  return false;
+}
+
+template <size_t N>
+string to_hex(const std::array<unsigned char, N>& digest) {
+  static const char digits[] = "0123456789ABCDEF";
+  string rv;
+  rv.reserve(N * 2);
+  for (const auto byte : digest) {
+    rv.push_back(digits[byte >> 4]);
+    rv.push_back(digits[byte & 0xF]);
+  }
+  return rv;
+}
+
+string to_hex(const pair64& md5) {
+  auto rv = from_pair64(md5);
+  transform(rv.cbegin(), rv.cend(), rv.begin(), ::toupper);
+  return rv;
+}
+
+/** What QUERYPREFIX or NEAREST found. */
+struct PrefixMatches {
+  uint64_t count{0};
+  vector<string> hashes;
+};
+
+/** Adds the hashes in the sorted range [first, last) that start with
+ * prefix to matches.  Those that start with it lie between prefix
+ * padded out with 0s and prefix padded out with Fs.
+ * @param digits how many hex digits the range's hashes have
+ * @param decode turns that many hex digits into one of the range's
+ * hashes
+ */
+template <typename Iterator, typename Decode>
+void match_prefix(Iterator first, Iterator last, const size_t digits,
+                  Decode decode, const string& prefix,
+                  const HashOverlay& changes, const size_t limit,
+                  PrefixMatches& matches) {
+  if (prefix.size() > digits) return;
+  const auto padding = digits - prefix.size();
+  const auto low = std::lower_bound(first, last,
+                                    decode(prefix + string(padding, '0')));
+  const auto high = std::upper_bound(low, last,
+                                     decode(prefix + string(padding, 'F')));
+  for (auto iter = low; iter != high; ++iter) {
+    const auto hash = to_hex(*iter);
+    if (changes.removed.count(hash)) continue;
+    ++matches.count;
+    if (matches.hashes.size() < limit) matches.hashes.push_back(hash);
+  }
+}
+
+/** Finds the hashes in data that start with prefix, listing up to
+ * limit of them.  Hashes added in changes count, and those deleted in
+ * it don't.
+ * @param prefix hex digits, in upper case
+ */
+PrefixMatches find_prefix(const HashData& data, const string& prefix,
+                          const size_t limit,
+                          const std::shared_ptr<const HashOverlay>& changes) {
+  PrefixMatches rv;
+  match_prefix(data.md5s.cbegin(), data.md5s.cend(), 32, to_pair64, prefix,
+               *changes, limit, rv);
+  match_prefix(data.sha1s.cbegin(), data.sha1s.cend(), 40, to_sha1, prefix,
+               *changes, limit, rv);
+  match_prefix(data.sha256s.cbegin(), data.sha256s.cend(), 64, to_sha256,
+               prefix, *changes, limit, rv);
+  for (auto iter = changes->added.lower_bound(prefix);
+       iter != changes->added.cend() && 0 == iter->compare(0, prefix.size(),
+                                                           prefix);
+       ++iter) {
+    if (is_present_in_hashes(data, *iter)) continue;
+    ++rv.count;
+    if (rv.hashes.size() < limit) rv.hashes.push_back(*iter);
+  }
+  return rv;
+}
+
+/** How many hex digits two halves of an MD5 differ in. */
+unsigned nibble_distance(unsigned long long lhs, unsigned long long rhs) {
+  auto diff = lhs ^ rhs;
+  // Fold each digit's bits into its lowest one, then count those.
+  diff = (diff | diff >> 1 | diff >> 2 | diff >> 3) & 0x1111111111111111ULL;
+  return static_cast<unsigned>(std::bitset<64>(diff).count());
+}
+
+unsigned nibble_distance(const pair64& lhs, const pair64& rhs) {
+  return nibble_distance(lhs.first, rhs.first) +
+         nibble_distance(lhs.second, rhs.second);
+}
+
+template <size_t N>
+unsigned nibble_distance(const std::array<unsigned char, N>& lhs,
+                         const std::array<unsigned char, N>& rhs) {
+  unsigned rv{0};
+  for (size_t idx = 0; idx < N; ++idx) {
+    const auto diff = lhs[idx] ^ rhs[idx];
+    rv += (0 != (diff & 0xF0)) + (0 != (diff & 0x0F));
+  }
+  return rv;
+}
+
+/** Adds the hashes in [first, last) within distance hex digits of
+ * target to matches, by looking at every one of them.
+ */
+template <typename Iterator, typename T>
+void match_nearest(Iterator first, Iterator last, const T& target,
+                   const unsigned distance, const HashOverlay& changes,
+                   PrefixMatches& matches) {
+  for (; first != last; ++first) {
+    if (nibble_distance(*first, target) > distance) continue;
+    const auto hash = to_hex(*first);
+    if (changes.removed.count(hash)) continue;
+    ++matches.count;
+    if (matches.hashes.size() < max_prefix_listed)
+      matches.hashes.push_back(hash);
+  }
+}
+
+/** Finds the hashes in data of the same type as hash and within
+ * distance hex digits of it.  Hashes added in changes count, and those
+ * deleted in it don't.
+ * @param hash a well-formed hash, in upper case
+ */
+PrefixMatches find_nearest(const HashData& data, const string& hash,
+                           const unsigned distance,
+                           const HashOverlay& changes) {
+  PrefixMatches rv;
+  switch (hash.size()) {
+    case 40:
+      match_nearest(data.sha1s.cbegin(), data.sha1s.cend(), to_sha1(hash),
+                    distance, changes, rv);
+      break;
+    case 64:
+      match_nearest(data.sha256s.cbegin(), data.sha256s.cend(),
+                    to_sha256(hash), distance, changes, rv);
+      break;
+    default:
+      match_nearest(data.md5s.cbegin(), data.md5s.cend(), to_pair64(hash),
+                    distance, changes, rv);
+      break;
+  }
+  for (const auto& added : changes.added) {
+    if (added.size() != hash.size() || is_present_in_hashes(data, added))
+      continue;
+    unsigned differing{0};
+    for (size_t idx = 0; idx < hash.size(); ++idx)
+      differing += added[idx] != hash[idx];
+    if (differing > distance) continue;
+    ++rv.count;
+    if (rv.hashes.size() < max_prefix_listed) rv.hashes.push_back(added);
+  }
+  return rv;
+}
+
+/** QUERYPREFIX's and NEAREST's answer: OK, how many hashes matched, and
+ * those listed.
+ */
+string format_matches(const PrefixMatches& matches, const bool json) {
+  stringstream rv;
+  rv << "OK ";
+  if (json) {
+    rv << "{\"count\":" << matches.count << ",\"hashes\":[";
+    for (size_t idx = 0; idx < matches.hashes.size(); ++idx)
+      rv << (idx ? "," : "") << json_string(matches.hashes[idx]);
+    rv << "]}";
+  } else {
+    rv << matches.count;
+    for (const auto& hash : matches.hashes) rv << " " << hash;
+  }
+  rv << "\r\n";
+  return rv.str();
+}
+
+/** The file the sorted hashes in set got hash from, or null if it
+ * isn't there.
+ * @param tags which of sources each hash in set came from
+ */
+template <typename Set, typename T>
+const string* tagged_source(const Set& set,
+                            const vector<uint8_t>& tags,
+                            const vector<string>& sources, const T& hash) {
+  const auto found = std::lower_bound(set.cbegin(), set.cend(), hash);
+  if (set.cend() == found || !(*found == hash)) return nullptr;
+  return &sources.at(tags.at(found - set.cbegin()));
+}
+
+/** The --track-sources file data got hash from, or null if it isn't
+ * there.  Hashes added in changes come from the admin socket, and
+ * those deleted in it come from nowhere.
+ * @param hash a well-formed hash, in upper case
+ */
+const string* source_of(const HashData& data, const string& hash,
+                        const HashOverlay& changes) {
+  static const string admin_socket{"(admin socket)"};
+  if (changes.removed.count(hash)) return nullptr;
+  if (changes.added.count(hash)) return &admin_socket;
+  switch (hash.size()) {
+    case 40:
+      return tagged_source(data.sha1s, data.sha1_sources, data.sources,
+                           to_sha1(hash));
+    case 64:
+      return tagged_source(data.sha256s, data.sha256_sources, data.sources,
+                           to_sha256(hash));
+    default:
+      return tagged_source(data.md5s, data.md5_sources, data.sources,
+                           to_pair64(hash));
+  }
+}
+}  // namespace
+
+/** How many hashes are being served right now. */
+uint64_t loaded_hash_count() { return current_hashes()->size(); }
+
+namespace {
+/** The admin socket's changes to dataset; only the main hash files
+ * have any.
+ */
+std::shared_ptr<const HashOverlay> overlay_for(const string& dataset) {
+  static const auto none = std::make_shared<const HashOverlay>();
+  return dataset.empty() ? current_overlay() : none;
+}
+}  // namespace
+
+namespace {
+/** Looks up each hash in [first, last), counting them in the metrics,
+ * and hands each answer to report as soon as it's known.  Every one is
+ * answered from the same data, even if a reload or an admin command
+ * changes the hashes partway through.
+ * @param dataset the --dataset to look in, or empty for the main hash
+ * files; it must exist
+ * @return how many of the hashes were present
+ */
+uint64_t stream_lookups(vector<string>::const_iterator first,
+                        vector<string>::const_iterator last,
+                        const string& dataset,
+                        const std::function<void(bool)>& report) {
+  const auto started = steady_clock::now();
+  const auto count = static_cast<uint64_t>(distance(first, last));
+  uint64_t hits{0};
+  const auto data = current_hashes(dataset);
+  const auto changes = overlay_for(dataset);
+  // The cache is only for the main hash files; switching it between
+  // datasets would keep emptying it.
+  const auto cache = dataset.empty() ? query_cache.get() : nullptr;
+  string key;
+  for (; first != last; ++first) {
+    bool found;
+    // The cache is keyed on text, so it needs the case evened out for
+    // "abc..." and "ABC..." to share an entry.
+    if (cache) {
+      key.clear();
+      transform(first->cbegin(), first->cend(), back_inserter(key),
+                ::toupper);
+    }
+    if (!cache || !cache->find(data, key, found)) {
+      found = is_present_in_hashes(*data, *first);
+      if (cache) cache->insert(data, key, found);
+    }
+    if (!changes->empty()) found = changes->apply(*first, found);
+    hits += found ? 1 : 0;
+    report(found);
+  }
+  const std::chrono::duration<double> elapsed =
+      steady_clock::now() - started;
+  metrics.query_seconds.observe(elapsed);
+  if (0 < count)
+    metrics.hash_seconds.observe(elapsed / static_cast<double>(count));
+  ++metrics.queries;
+  metrics.hashes_queried += count;
+  metrics.hits += hits;
+  metrics.misses += count - hits;
+  return hits;
+}
 }  // namespace
 
-void handle_client(tcp::iostream& stream) {
-  const string ipaddr = stream.socket().remote_endpoint().address().to_string();
-  unsigned long long queries = 0;
+/** As stream_lookups(), but collects the answers. */
+vector<bool> lookup_hashes(vector<string>::const_iterator first,
+                           vector<string>::const_iterator last,
+                           const string& dataset) {
+  vector<bool> rv;
+  rv.reserve(static_cast<size_t>(distance(first, last)));
+  stream_lookups(first, last, dataset,
+                 [&rv](const bool found) { rv.push_back(found); });
+  return rv;
+}
+
+namespace {
+/** What FORMAT: has chosen.  After FORMAT: binary the client sends
+ * frames, not lines, for the rest of the session.
+ */
+enum class Format { Text, Json, Binary };
+
+/** One client's session: who it is and what it's agreed on so far.  It
+ * only talks to the client through the streams it's handed, so it can
+ * be driven from a stringstream as well as from a socket.
+ */
+struct Session {
+  explicit Session(const string& ipaddr) : peer{ipaddr} {}
+
+  /** Answers one line from the client.  A blank line ends the session
+   * just as BYE does.
+   * @param out where QUERY writes its answer as it goes
+   * @return the reply, as dispatch() gives it
+   */
+  string handle_line(const string& line, std::ostream& out);
+  /** Reads one frame from a client that chose FORMAT: binary and
+   * writes back its answer.
+   * @return false once the session is over: the client hung up, sent
+   * an empty frame or one of more than --max-query-hashes, or was
+   * rate limited
+   */
+  bool handle_frame(std::iostream& stream);
+
+  // Who the client is, for rate limiting and logging.
+  string peer;
+  // The major protocol version agreed on with VERSION:, or 0 if none
+  // has been.  FORMAT: needs one agreed on.
+  int protocol{0};
+  Format format{Format::Text};
+  // The --dataset DATASET selected; empty for the main hash files.
+  string dataset;
+  bool done{false};
+  unsigned long long queries{0};
+};
+
+/** Answers one command from a client, updating the session's state.
+ * Every command gets a reply, so a client can always tell a command
+ * the server didn't understand from a dropped connection.
+ * @param commands the command line, split on spaces; never empty
+ * @param out where QUERY writes its answer as it goes, since for a
+ * big batch that's a lot to hold on to
+ * @return the reply, including its line ending; empty for BYE, or
+ * once QUERY has written its answer to out
+ */
+string dispatch(const vector<string>& commands, Session& session,
+                std::ostream& out) {
+  const auto command = getCommand(commands.at(0));
+  if (1 == session.protocol && !is_protocol1_command(command))
+    return "NOT OK not in protocol 1.x\r\n";
+
+  switch (command) {
+    case Command::Version:
+      if (commands.size() < 2) return "NOT OK missing argument\r\n";
+      if (commands.size() != 2 || !is_supported_version(commands.at(1))) {
+        session.protocol = 0;
+        return "NOT OK\r\n";
+      }
+      session.protocol = '1' == commands.at(1).at(0) ? 1 : 2;
+      return "OK\r\n";
+
+    case Command::Bye:
+      session.done = true;
+      return "";
+
+    case Command::Status: {
+      const auto uptime =
+          duration_cast<seconds>(steady_clock::now() - start_time);
+      // Still one line, and still starting with the hash count and
+      // uptime, for clients that only read that far.
+      return "OK " + to_string(loaded_hash_count()) + " hashes, up " +
+             to_string(uptime.count()) + "s, " +
+             to_string(metrics.connections) + " connections served, " +
+             to_string(active_sessions) + " active, " +
+             to_string(metrics.queries) + " queries, " +
+             to_string(metrics.hashes_queried) + " hashes looked up\r\n";
+    }
+
+    case Command::Query: {
+      if (0 < max_query_hashes && commands.size() - 1 > max_query_hashes)
+        return "NOT OK too many hashes\r\n";
+      if (rate_limiter && !rate_limiter->allow(session.peer))
+        return "RATE LIMITED\r\n";
+      // The same answer encode_query_response() would give, a byte
+      // at a time.
+      const auto hit = invert_results ? miss_char : hit_char;
+      const auto miss = invert_results ? hit_char : miss_char;
+      auto hash = commands.cbegin() + 1;
+      out << (Format::Json == session.format ? "OK {" : "OK ");
+      const auto present = stream_lookups(
+          commands.cbegin() + 1, commands.cend(), session.dataset,
+          [&](const bool found) {
+            if (Format::Json != session.format) {
+              out.put(found ? hit : miss);
+              return;
+            }
+            out << (hash == commands.cbegin() + 1 ? "" : ",")
+                << json_string(*hash) << ":" << (found ? "true" : "false");
+            ++hash;
+          });
+      out << (Format::Json == session.format ? "}\r\n" : "\r\n");
+      session.queries += commands.size() - 1;
+      if (log_enabled(LogLevel::DEBUG))
+        log(LogLevel::DEBUG, session.peer + " queried " +
+                                 to_string(commands.size() - 1) +
+                                 " hashes, " + to_string(present) +
+                                 " present");
+      return "";
+    }
+
+    case Command::QueryPrefix: {
+      if (commands.size() < 2) return "NOT OK missing argument\r\n";
+      string prefix;
+      transform(commands.at(1).cbegin(), commands.at(1).cend(),
+                back_inserter(prefix), ::toupper);
+      if (commands.size() > 3 || prefix.size() < min_prefix_digits ||
+          prefix.size() > 64 ||
+          string::npos != prefix.find_first_not_of("0123456789ABCDEF"))
+        return "NOT OK bad prefix\r\n";
+      size_t limit{0};
+      if (commands.size() == 3) {
+        const auto& text = commands.at(2);
+        if (text.size() > 3 ||
+            string::npos != text.find_first_not_of("0123456789") ||
+            std::stoul(text) > max_prefix_listed)
+          return "NOT OK bad limit\r\n";
+        limit = std::stoul(text);
+      }
+      // Neither a Bloom filter nor a compact set can say which hashes
+      // lie in a range.
+      const auto data = current_hashes(session.dataset);
+      if (data->filter || data->compact_md5s)
+        return "NOT OK prefixes need the full hash set\r\n";
+      if (rate_limiter && !rate_limiter->allow(session.peer))
+        return "RATE LIMITED\r\n";
+
+      return format_matches(
+          find_prefix(*data, prefix, limit, overlay_for(session.dataset)),
+          Format::Json == session.format);
+    }
+
+    case Command::Nearest: {
+      if (!nearest_enabled) return "NOT OK NEAREST isn't enabled\r\n";
+      if (commands.size() < 3) return "NOT OK missing argument\r\n";
+      string hash;
+      transform(commands.at(1).cbegin(), commands.at(1).cend(),
+                back_inserter(hash), ::toupper);
+      if (commands.size() > 3 ||
+          (hash.size() != 32 && hash.size() != 40 && hash.size() != 64) ||
+          string::npos != hash.find_first_not_of("0123456789ABCDEF"))
+        return "NOT OK bad hash\r\n";
+      const auto& text = commands.at(2);
+      if (1 != text.size() || text[0] < '0' ||
+          text[0] > static_cast<char>('0' + max_nearest_distance))
+        return "NOT OK bad distance\r\n";
+      if (rate_limiter && !rate_limiter->allow(session.peer))
+        return "RATE LIMITED\r\n";
+      const auto data = current_hashes(session.dataset);
+      return format_matches(
+          find_nearest(*data, hash, static_cast<unsigned>(text[0] - '0'),
+                       *overlay_for(session.dataset)),
+          Format::Json == session.format);
+    }
+
+    case Command::Dataset:
+      if (commands.size() < 2) return "NOT OK missing argument\r\n";
+      if (commands.size() > 2) return "NOT OK unknown dataset\r\n";
+      if ("default" == commands.at(1)) {
+        session.dataset.clear();
+        return "OK\r\n";
+      }
+      if (!current_hashes(commands.at(1))) return "NOT OK unknown dataset\r\n";
+      session.dataset = commands.at(1);
+      return "OK\r\n";
+
+    case Command::QuerySource: {
+      if (commands.size() < 2) return "NOT OK missing argument\r\n";
+      string hash;
+      transform(commands.at(1).cbegin(), commands.at(1).cend(),
+                back_inserter(hash), ::toupper);
+      if (commands.size() > 2 ||
+          (hash.size() != 32 && hash.size() != 40 && hash.size() != 64) ||
+          string::npos != hash.find_first_not_of("0123456789ABCDEF"))
+        return "NOT OK bad hash\r\n";
+      if (current_hashes()->sources.empty())
+        return "NOT OK sources aren't tracked\r\n";
+      if (rate_limiter && !rate_limiter->allow(session.peer))
+        return "RATE LIMITED\r\n";
+
+      // The main hash files first, then each --dataset in turn; the
+      // first that has the hash is the one reported.
+      auto datasets = dataset_names();
+      datasets.insert(datasets.begin(), "");
+      for (const auto& dataset : datasets) {
+        const auto data = current_hashes(dataset);
+        if (!data) continue;
+        const auto source = source_of(*data, hash, *overlay_for(dataset));
+        if (!source) continue;
+        const auto name = dataset.empty() ? "default" : dataset;
+        if (Format::Json == session.format)
+          return "OK {\"dataset\":" + json_string(name) + ",\"file\":" +
+                 json_string(*source) + "}\r\n";
+        return "OK " + name + " " + *source + "\r\n";
+      }
+      return Format::Json == session.format ? "OK null\r\n" : "OK NONE\r\n";
+    }
+
+    case Command::Upshift:
+    case Command::Downshift:
+      return "NOT OK\r\n";
+
+    case Command::Format: {
+      if (commands.size() < 2) return "NOT OK missing argument\r\n";
+      string format;
+      if (commands.size() == 2)
+        transform(commands.at(1).cbegin(), commands.at(1).cend(),
+                  back_inserter(format), ::tolower);
+      if (session.protocol &&
+          (format == "json" || format == "text" || format == "binary")) {
+        session.format = format == "json"     ? Format::Json
+                         : format == "binary" ? Format::Binary
+                                              : Format::Text;
+        return "OK\r\n";
+      }
+      return "NOT OK\r\n";
+    }
+
+    case Command::Unknown:
+      break;
+  }
+  return "NOT OK unknown command\r\n";
+}
+}  // namespace
+
+namespace {
+enum class LineRead { Line, End, TooLong };
+
+/** Reads a line much as getline() does, but stops once the line has
+ * passed --max-line-bytes rather than buffering whatever a client
+ * sends while it waits for a newline that may never come.
+ */
+LineRead read_line(std::istream& stream, string& line) {
+  auto* const buffer = stream.rdbuf();
+  for (;;) {
+    const auto ch = buffer->sbumpc();
+    if (std::char_traits<char>::eof() == ch)
+      return line.empty() ? LineRead::End : LineRead::Line;
+    if ('\n' == ch) return LineRead::Line;
+    if (0 < max_line_bytes && line.size() >= max_line_bytes)
+      return LineRead::TooLong;
+    line.push_back(std::char_traits<char>::to_char_type(ch));
+  }
+}
+bool Session::handle_frame(std::iostream& stream) {
+  string frame(4, '\0');
+  if (!stream.read(&frame[0], 4)) return false;
+  const auto length = binary_frame_length(frame);
+  if (4 == length) return false;
+  const auto count = (length - 4) / 16;
+  if (0 < max_query_hashes && count > max_query_hashes) {
+    log(LogLevel::WARN, peer + " sent a frame of " + to_string(count) +
+                            " hashes; closing the session");
+    stream << "NOT OK too many hashes\r\n";
+    return false;
+  }
+  // Grown as the hashes arrive, so that a count the client doesn't
+  // follow through on costs no more memory than what it did send.
+  while (frame.size() < length) {
+    const auto have = frame.size();
+    const auto chunk = std::min<size_t>(length - have, 65536);
+    frame.resize(have + chunk);
+    if (!stream.read(&frame[have], static_cast<std::streamsize>(chunk))) {
+      log(LogLevel::WARN, peer + " hung up partway through a frame");
+      return false;
+    }
+  }
+  if (rate_limiter && !rate_limiter->allow(peer)) {
+    log(LogLevel::WARN, peer + " was rate limited; closing the session");
+    return false;
+  }
+
+  vector<pair64> md5s;
+  decode_binary_query(frame, md5s);
+  string().swap(frame);
+  // Looked up the same way as hex ones, so the cache and the admin
+  // socket's changes apply alike.
+  vector<string> hashes;
+  hashes.reserve(md5s.size());
+  for (const auto& md5 : md5s) hashes.push_back(to_hex(md5));
+  vector<bool> results;
+  results.reserve(hashes.size());
+  const auto present = stream_lookups(
+      hashes.cbegin(), hashes.cend(), dataset,
+      [&](const bool found) { results.push_back(found != invert_results); });
+  stream << encode_binary_response(results);
+  queries += hashes.size();
+  if (log_enabled(LogLevel::DEBUG))
+    log(LogLevel::DEBUG, peer + " queried " + to_string(hashes.size()) +
+                             " hashes in a frame, " + to_string(present) +
+                             " present");
+  return true;
+}
+
+string Session::handle_line(const string& line, std::ostream& out) {
+  // trim leading/following whitespace
+  const auto first = line.find_first_not_of("\t\n\v\f\r ");
+  // trips on the empty string, or a string of pure whitespace
+  if (string::npos == first) {
+    done = true;
+    return "";
+  }
+  const auto last = line.find_last_not_of("\t\n\v\f\r ");
+  return dispatch(tokenize(line.substr(first, last - first + 1)), *this,
+                  out);
+}
+
+}  // namespace
+
+void handle_client(std::iostream& stream, const string& ipaddr) {
+  Session session{ipaddr};
   try {
-    bool byebye = false;
-    while (stream && (! byebye)) {
+    while (stream && !session.done && (! stop_requested)) {
+      if (Format::Binary == session.format) {
+        if (!session.handle_frame(stream)) break;
+        continue;
+      }
       string line;
-      getline(stream, line);
-      // trim leading/following whitespace
-      auto end_ws = line.find_last_not_of("\t\n\v\f\r ");
-
-      // trips on the empty string, or a string of pure whitespace
-      if (line.size() == 0 || end_ws == string::npos)
-	break;
-      
-      auto head_iter = line.cbegin() + line.find_first_not_of("\t\n\v\f\r ");
-      auto end_iter = line.cbegin() + end_ws + 1;
-      auto commands = tokenize(string(head_iter, end_iter));
-      
-      switch (getCommand(commands.at(0))) {
-        case Command::Version:
-          stream << "OK\r\n";
-          break;
-
-        case Command::Bye:
-	  byebye = true;
-          break;
-
-        case Command::Status:
-          stream << "NOT SUPPORTED\r\n";
-          break;
-
-        case Command::Query: {
-          stringstream rv;
-          rv << "OK ";
-          for (size_t idx = 1; idx < commands.size(); ++idx)
-            rv << (is_present_in_hashes(commands.at(idx)) ? "1" : "0");
-          rv << "\r\n";
-          queries += (commands.size() - 1);
-          stream << rv.str();
-          break;
-        }
-
-        case Command::Upshift:
-          stream << "NOT OK\r\n";
-          break;
-
-        case Command::Downshift:
-          stream << "NOT OK\r\n";
-          break;
-
-        case Command::Unknown:
-          stream << "NOT OK\r\n";
-	  byebye = true;
+      const auto read = read_line(stream, line);
+      // A client that hangs up without saying BYE gets treated as
+      // though it had.
+      if (LineRead::End == read) break;
+      if (LineRead::TooLong == read) {
+        log(LogLevel::WARN, ipaddr + " sent a line of more than " +
+                                to_string(max_line_bytes) +
+                                " bytes; closing the session");
+        stream << "NOT OK line too long\r\n";
+        break;
       }
+      stream << session.handle_line(line, stream);
     }
   } catch (std::exception& e) {
     log(LogLevel::ALERT, string("Error: ") + e.what());
@@ -154,7 +804,12 @@ void handle_client(tcp::iostream& stream) {
     // fall-through here to function return
   }
 
+  // Push out anything still buffered; the caller owns the connection
+  // and closes it once we're done.
+  stream.flush();
+
   stringstream status_msg;
-  status_msg << ipaddr << " closed session after " << queries << " queries";
+  status_msg << ipaddr << " closed session after " << session.queries
+             << " queries";
   log(LogLevel::ALERT, status_msg.str());
 }
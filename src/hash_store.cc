@@ -0,0 +1,720 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <errno.h>
+#include <sys/mman.h>
+#include <sys/stat.h>
+#include <algorithm>
+#include <array>
+#include <cstring>
+#include <fstream>
+#include <functional>
+#include <regex>
+#include <new>
+#include <stdexcept>
+#include <string>
+#include <system_error>
+#include <thread>
+#include <utility>
+#include <vector>
+#include "engine.h"
+
+using std::binary_search;
+using std::ifstream;
+using std::invalid_argument;
+using std::pair;
+using std::regex;
+using std::sort;
+using std::string;
+using std::to_string;
+using std::transform;
+using std::vector;
+
+namespace {
+/** Passes msg on to the options' log, if they have one. */
+void report(const LoadOptions& options, const LogLevel level,
+            const string& msg) {
+  if (nullptr != options.log) options.log(level, msg);
+}
+
+/** How many lines the loader reads before handing them to workers. */
+const size_t lines_per_chunk{1000000};
+
+/** One worker's share of a chunk of the hash file, once parsed. */
+struct ParsedLines {
+  vector<pair64> md5s;
+  vector<sha1_digest> sha1s;
+  vector<sha256_digest> sha256s;
+  uint64_t bad_lines{0};
+  // The first few bad lines, by line number within the file.
+  vector<pair<uint64_t, string>> bad_examples;
+  bool out_of_memory{false};
+
+  void clear() {
+    md5s.clear();
+    sha1s.clear();
+    sha256s.clear();
+    bad_lines = 0;
+    bad_examples.clear();
+  }
+};
+
+/** Whether a file's first line is the header of an RDS NSRLFile.txt,
+ * which names its columns "SHA-1","MD5","CRC32","FileName", and so on.
+ */
+bool is_rds_header(const string& line) {
+  static const string header{"\"SHA-1\",\"MD5\","};
+  return 0 == line.compare(0, header.size(), header);
+}
+
+/** Strips the double quotes from around a CSV field, if it has them. */
+string unquote(const string& field) {
+  if (2 <= field.size() && '"' == field.front() && '"' == field.back())
+    return field.substr(1, field.size() - 2);
+  return field;
+}
+
+/** Adds the MD5 (and, with rds_sha1, the SHA-1) from one record of
+ * an NSRLFile.txt, already uppercased.  Only the first two columns
+ * are looked at, so commas later on, e.g. in file names, don't matter.
+ * @return false if the record doesn't start with a SHA-1 and an MD5
+ */
+bool add_rds_record(const string& line, const regex& hash_re,
+                    const bool rds_sha1, ParsedLines& out) {
+  const auto first_comma = line.find(',');
+  if (string::npos == first_comma) return false;
+  const auto second_comma = line.find(',', first_comma + 1);
+  const auto sha1 = unquote(line.substr(0, first_comma));
+  const auto md5 = unquote(line.substr(
+      first_comma + 1, string::npos == second_comma
+                           ? string::npos
+                           : second_comma - first_comma - 1));
+  if (32 != md5.size() || !regex_match(md5, hash_re)) return false;
+  if (rds_sha1 && (40 != sha1.size() || !regex_match(sha1, hash_re)))
+    return false;
+
+  out.md5s.emplace_back(to_pair64(md5));
+  if (rds_sha1) out.sha1s.emplace_back(to_sha1(sha1));
+  return true;
+}
+
+/** How many bad lines a corrupt hash file's error message quotes. */
+const size_t bad_examples_shown{3};
+
+/** Validates and converts lines [first, last) of a chunk, which starts
+ * at line first_line of the file.  Lines are bare hashes, or with
+ * rds_csv, records from an NSRLFile.txt.  This runs on a worker
+ * thread, so rather than logging or exiting it counts the bad lines it
+ * sees and keeps a few of them as examples.
+ */
+void parse_lines(const vector<string>& lines, const size_t first,
+                 const size_t last, const uint64_t first_line,
+                 const bool rds_csv, const bool rds_sha1,
+                 const regex& hash_re, ParsedLines& out) {
+  try {
+    for (auto idx = first; idx < last; ++idx) {
+      string line{lines[idx]};
+      transform(line.begin(), line.end(), line.begin(), ::toupper);
+      // NIST ships NSRLFile.txt with DOS line endings.
+      if (rds_csv && !line.empty() && '\r' == line.back()) line.pop_back();
+      if (0 == line.size()) continue;
+
+      const bool good = rds_csv ? add_rds_record(line, hash_re, rds_sha1, out)
+                                : regex_match(line.cbegin(), line.cend(),
+                                              hash_re);
+      if (!good) {
+        if (out.bad_examples.size() < bad_examples_shown)
+          out.bad_examples.emplace_back(first_line + idx, lines[idx]);
+        ++out.bad_lines;
+        continue;
+      }
+      if (rds_csv) continue;
+
+      switch (line.size()) {
+        case 32:
+          out.md5s.emplace_back(to_pair64(line));
+          break;
+        case 40:
+          out.sha1s.emplace_back(to_sha1(line));
+          break;
+        default:
+          out.sha256s.emplace_back(to_sha256(line));
+          break;
+      }
+    }
+  } catch (std::bad_alloc&) {
+    out.out_of_memory = true;
+  }
+}
+
+/** Sorts a vector by splitting it into one run per worker, sorting the
+ * runs concurrently, then merging neighbouring runs in parallel until
+ * only one is left.  Small sets aren't worth the threads.
+ */
+template <typename T>
+void parallel_sort(vector<T>& set) {
+  const auto workers = worker_count();
+  if (workers < 2 || set.size() < lines_per_chunk) {
+    sort(set.begin(), set.end());
+    return;
+  }
+
+  vector<size_t> bounds;
+  for (unsigned idx = 0; idx <= workers; ++idx)
+    bounds.push_back(set.size() * idx / workers);
+
+  const auto base = set.begin();
+  vector<std::thread> threads;
+  for (size_t idx = 0; idx + 1 < bounds.size(); ++idx)
+    threads.emplace_back(
+        [=]() { sort(base + bounds[idx], base + bounds[idx + 1]); });
+  for (auto& thread : threads) thread.join();
+
+  while (bounds.size() > 2) {
+    vector<size_t> merged;
+    threads.clear();
+    for (size_t idx = 0; idx + 2 < bounds.size(); idx += 2) {
+      threads.emplace_back([=]() {
+        std::inplace_merge(base + bounds[idx], base + bounds[idx + 1],
+                           base + bounds[idx + 2]);
+      });
+      merged.push_back(bounds[idx]);
+    }
+    // An odd run out has nothing to merge with this round.
+    if (0 == bounds.size() % 2) merged.push_back(bounds[bounds.size() - 2]);
+    merged.push_back(bounds.back());
+    for (auto& thread : threads) thread.join();
+    bounds.swap(merged);
+  }
+}
+
+/** Sorts a freshly-loaded set of hashes and drops any duplicates,
+ * which are common when several RDS subsets get merged into one file.
+ */
+template <typename T>
+void sort_and_dedup(vector<T>& set, const LoadOptions& options) {
+  parallel_sort(set);
+
+  const auto before = set.size();
+  set.erase(std::unique(set.begin(), set.end()), set.end());
+  if (set.size() != before)
+    report(options, LogLevel::INFO,
+           "removed " + to_string(before - set.size()) + " duplicate hashes");
+}
+
+/** As sort_and_dedup(), but keeps each hash's source tag with it.  Of
+ * the copies of a hash, the one from the earliest file is kept.  For
+ * the length of the sort the hashes are held twice over, once with
+ * their tags; --track-sources documents the cost.
+ */
+template <typename T>
+void sort_and_dedup(vector<T>& set, vector<uint8_t>& tags,
+                    const LoadOptions& options) {
+  vector<pair<T, uint8_t>> tagged;
+  tagged.reserve(set.size());
+  for (size_t idx = 0; idx < set.size(); ++idx)
+    tagged.emplace_back(set[idx], tags[idx]);
+  vector<T>().swap(set);
+  vector<uint8_t>().swap(tags);
+  parallel_sort(tagged);
+
+  const auto before = tagged.size();
+  tagged.erase(std::unique(tagged.begin(), tagged.end(),
+                           [](const pair<T, uint8_t>& lhs,
+                              const pair<T, uint8_t>& rhs) {
+                             return lhs.first == rhs.first;
+                           }),
+               tagged.end());
+  if (tagged.size() != before)
+    report(options, LogLevel::INFO,
+           "removed " + to_string(before - tagged.size()) +
+               " duplicate hashes");
+  set.reserve(tagged.size());
+  tags.reserve(tagged.size());
+  for (const auto& entry : tagged) {
+    set.push_back(entry.first);
+    tags.push_back(entry.second);
+  }
+}
+
+bool is_gzip_file(const string& path) {
+  std::array<char, 2> header;
+  ifstream infile{path.c_str(), std::ios::binary};
+  infile.read(header.data(), header.size());
+  return infile.gcount() == 2 && '\x1f' == header[0] && '\x8b' == header[1];
+}
+
+/** Checks whether a file starts with the header every SQLite 3
+ * database begins with, which is how NIST ships RDSv3.
+ */
+bool is_sqlite_database(const string& path) {
+  static const char magic[] = "SQLite format 3";  // 16 bytes with the NUL
+  std::array<char, sizeof(magic)> header;
+  ifstream infile{path.c_str(), std::ios::binary};
+  infile.read(header.data(), header.size());
+  return infile.gcount() == static_cast<std::streamsize>(header.size()) &&
+         std::equal(header.cbegin(), header.cend(), magic);
+}
+
+/** Adds every MD5, SHA-1 and SHA-256 in an RDSv3 SQLite database to
+ * sets.  Like the text loader, it throws a LoadError if anything fails.
+ */
+void load_database(const string& path, HashSets& sets,
+                   const LoadOptions& options) {
+  using Kind = LoadError::Kind;
+#ifdef HAVE_SQLITE3
+  report(options, LogLevel::INFO, "reading RDS database " + path);
+  try {
+    load_rds_database(path, sets.md5s, sets.sha1s, sets.sha256s);
+  } catch (std::bad_alloc&) {
+    throw LoadError(Kind::OutOfMemory, "couldn't allocate enough memory");
+  } catch (std::exception& e) {
+    throw LoadError(Kind::Unreadable,
+                    "couldn't read RDS database " + path + ": " + e.what());
+  }
+#else
+  (void)sets;
+  (void)options;
+  throw LoadError(Kind::Unreadable,
+                  path + " is an RDS database, but this nsrlsvr was built "
+                         "without SQLite support");
+#endif
+}
+
+/** Adds the MD5, SHA-1 and SHA-256 hashes in a text file to sets.
+ * Each line must be either blank, 32 hexadecimal digits (an MD5), 40
+ * hexadecimal digits (a SHA-1), or 64 hexadecimal digits (a SHA-256).
+ * If the file can't be opened or doesn't conform to this, it throws a
+ * LoadError saying why, leaving sets incomplete.
+ */
+void load_text_file(const string& path, HashSets& sets,
+                    const LoadOptions& options) {
+  using Kind = LoadError::Kind;
+  const regex hash_re{"^([A-Fa-f0-9]{32}|[A-Fa-f0-9]{40}|[A-Fa-f0-9]{64})$"};
+  uint64_t hash_count{0};
+
+  // As of this writing, the full RDS had about 81 million entries.
+  // When a vector needs to grow, it normally does so by doubling
+  // the former allocation, so that at that size the application
+  // briefly needs three times the RAM the MD5s take up.  Reserving
+  // room for every line the file could hold avoids that.  Each hash
+  // takes at least 33 bytes, newline included, which makes for a close
+  // bound on nsrlupdate's output.  An RDS NSRLFile.txt has much longer
+  // lines, so the reserve stops at 100 million, a little more than the
+  // RDS has.  A small file, a named dataset or a library user's file
+  // gets a small reserve.  A gzipped file's size says too little, so
+  // it just grows as it's read.
+  //
+  // Failure to reserve this block of memory is non-recoverable.
+  // Don't even try.  Just report the error and bail out.  Let the end
+  // user worry about installing more RAM.
+  struct stat info;
+  if (!is_gzip_file(path) && 0 == stat(path.c_str(), &info)) {
+    const auto wanted =
+        sets.md5s.size() +
+        std::min<size_t>(static_cast<size_t>(info.st_size) / 33, 100000000);
+    try {
+      if (sets.md5s.capacity() < wanted) sets.md5s.reserve(wanted);
+    } catch (std::bad_alloc&) {
+      throw LoadError(Kind::OutOfMemory, "couldn't reserve enough memory");
+    }
+  }
+
+  // Compressed files are recognized by their magic bytes, so one
+  // doesn't need a .gz name to be decompressed.
+  std::filebuf plain;
+  std::istream infile{nullptr};
+#ifdef HAVE_ZLIB
+  std::unique_ptr<GzipBuffer> gzip;
+#endif
+  if (is_gzip_file(path)) {
+#ifdef HAVE_ZLIB
+    gzip.reset(new GzipBuffer(path));
+    if (!gzip->is_open()) open_failed("hashes file " + path);
+    infile.rdbuf(gzip.get());
+#else
+    throw LoadError(Kind::Unreadable,
+                    path + " is gzip-compressed, but nsrlsvr "
+                                      "was built without zlib");
+#endif
+  } else {
+    if (nullptr == plain.open(path.c_str(), std::ios::in))
+      open_failed("hashes file " + path);
+    infile.rdbuf(&plain);
+  }
+
+  // Parsing is the slow part of a load: every line gets a regex check
+  // and a hex conversion.  Lines are read in chunks and each chunk is
+  // split between worker threads.  The reading itself stays serial.
+  // Progress is checked between chunks, so a chunk mustn't span more
+  // than one --progress-interval.
+  const auto workers = worker_count();
+  const auto progress_interval = options.progress_interval;
+  const size_t chunk_lines =
+      0 < progress_interval && progress_interval < lines_per_chunk
+          ? static_cast<size_t>(progress_interval)
+          : lines_per_chunk;
+  vector<string> chunk;
+  vector<ParsedLines> parsed(workers);
+  // Once a bad line turns up the load is doomed, but the rest of the
+  // file is still checked: the number of bad lines is the best clue to
+  // what's wrong (every line bad suggests the wrong file or column).
+  uint64_t line_count{0};
+  uint64_t bad_lines{0};
+  vector<pair<uint64_t, string>> bad_examples;
+
+  // NIST's own NSRLFile.txt can be read as it comes, without running
+  // nsrlupdate first.  Its header line gives it away.
+  string first_line;
+  const bool got_line = static_cast<bool>(getline(infile, first_line));
+  const bool rds_csv = got_line && is_rds_header(first_line);
+  if (rds_csv) {
+    report(options, LogLevel::INFO,
+           path + " is an RDS NSRLFile.txt; reading its " +
+               (options.rds_sha1 ? "MD5 and SHA-1" : "MD5") + " column");
+    ++line_count;
+  } else if (got_line) {
+    chunk.emplace_back(std::move(first_line));
+  }
+
+  while (infile) {
+    string line;
+    while (chunk.size() < chunk_lines && getline(infile, line))
+      chunk.emplace_back(std::move(line));
+
+    vector<std::thread> threads;
+    for (unsigned idx = 0; idx < workers; ++idx) {
+      const auto first = chunk.size() * idx / workers;
+      const auto last = chunk.size() * (idx + 1) / workers;
+      threads.emplace_back(parse_lines, std::cref(chunk), first, last,
+                           line_count + 1, rds_csv, options.rds_sha1,
+                           std::cref(hash_re), std::ref(parsed[idx]));
+    }
+    for (auto& thread : threads) thread.join();
+    line_count += chunk.size();
+    chunk.clear();
+
+    const auto before = hash_count;
+    for (auto& result : parsed) {
+      if (result.out_of_memory)
+        throw LoadError(Kind::OutOfMemory, "couldn't allocate enough memory");
+      bad_lines += result.bad_lines;
+      for (auto& example : result.bad_examples)
+        if (bad_examples.size() < bad_examples_shown)
+          bad_examples.emplace_back(std::move(example));
+      if (0 < bad_lines) {
+        result.clear();
+        continue;
+      }
+      // Note that if a vector runs out of reserved room it will
+      // attempt to make a new allocation double the size of the
+      // last.  That means the application will at least briefly
+      // need *three times* the expected RAM -- one for the data set
+      // and two for the newly-allocated chunk.  Given we're talking
+      // about multiple gigs of RAM, this needs to consider the
+      // possibility of a RAM allocation failure.
+      try {
+        sets.md5s.insert(sets.md5s.end(), result.md5s.cbegin(),
+                         result.md5s.cend());
+        sets.sha1s.insert(sets.sha1s.end(), result.sha1s.cbegin(),
+                          result.sha1s.cend());
+        sets.sha256s.insert(sets.sha256s.end(), result.sha256s.cbegin(),
+                            result.sha256s.cend());
+      } catch (std::bad_alloc&) {
+        throw LoadError(Kind::OutOfMemory, "couldn't allocate enough memory");
+      }
+      hash_count += result.md5s.size() + result.sha1s.size() +
+                    result.sha256s.size();
+      result.clear();
+    }
+    if (0 < progress_interval &&
+        hash_count / progress_interval > before / progress_interval)
+      report(options, LogLevel::INFO, "loaded " + to_string(hash_count) +
+                                          " hashes from " + path +
+                                          " so far");
+  }
+  // Reading stops at the first error just as it does at the end of the
+  // file, so tell the two apart before trusting what was read.  A
+  // directory passed as --file opens fine but fails here.
+  if (infile.bad())
+    throw LoadError(Kind::Unreadable,
+                    "couldn't read hashes file " + path);
+#ifdef HAVE_ZLIB
+  if (gzip && !gzip->error().empty())
+    throw LoadError(Kind::Corrupt, "couldn't decompress hashes file " +
+                                       path + ": " +
+                                       gzip->error());
+#endif
+
+  if (0 < bad_lines) {
+    string message{path + " appears corrupt!  Loading no hashes.  " +
+                   to_string(bad_lines) + " of " +
+                   to_string(line_count - (rds_csv ? 1 : 0)) +
+                   (rds_csv ? " records don't start with a SHA-1 and MD5,"
+                            : " lines aren't hashes,") +
+                   " including"};
+    for (const auto& example : bad_examples) {
+      // A whole CSV record is more than anyone needs to see.
+      const auto text = 60 < example.second.size()
+                            ? example.second.substr(0, 60) + "..."
+                            : example.second;
+      message += (&example == &bad_examples.front() ? " line " : ", line ") +
+                 to_string(example.first) + ": \"" + text + "\"";
+    }
+    throw LoadError(Kind::Corrupt, message);
+  }
+
+}
+
+/** load_hashes() without its exceptions turned into LoadErrors. */
+void read_hashes(HashSets& sets, const vector<string>& files,
+                 const LoadOptions& options) {
+  for (const auto& path : files) {
+    const auto before = sets.size();
+    if (is_sqlite_database(path))
+      load_database(path, sets, options);
+    else
+      load_text_file(path, sets, options);
+    report(options, LogLevel::INFO, "read in " +
+                                        to_string(sets.size() - before) +
+                                        " hashes from " + path);
+    if (options.track_sources) {
+      // Everything past the tags so far came from this file.
+      const auto tag = static_cast<uint8_t>(sets.sources.size());
+      sets.md5_sources.resize(sets.md5s.size(), tag);
+      sets.sha1_sources.resize(sets.sha1s.size(), tag);
+      sets.sha256_sources.resize(sets.sha256s.size(), tag);
+      sets.sources.push_back(path);
+    }
+  }
+
+  if (options.track_sources) {
+    sort_and_dedup(sets.md5s, sets.md5_sources, options);
+    sort_and_dedup(sets.sha1s, sets.sha1_sources, options);
+    sort_and_dedup(sets.sha256s, sets.sha256_sources, options);
+  } else {
+    sort_and_dedup(sets.md5s, options);
+    sort_and_dedup(sets.sha1s, options);
+    sort_and_dedup(sets.sha256s, options);
+  }
+
+  report(options, LogLevel::INFO, "successfully loaded " +
+                                      to_string(sets.size()) +
+                                      " distinct hashes");
+}
+
+/** Whether data's Bloom filter answers lookups on its own, rather than
+ * screening them for a mapped index as with --bloom-verify.
+ */
+bool filter_only(const HashData& data) {
+  return data.filter && 0 == data.md5s.size();
+}
+
+bool contains_md5(const HashData& data, const pair64& md5) {
+  if (data.filter) {
+    if (!data.filter->contains(bloom_key(md5))) return false;
+    // Otherwise the index has the last word.
+    if (filter_only(data)) return true;
+  }
+  if (data.compact_md5s) return data.compact_md5s->contains(md5);
+  if (data.md5_shards) return data.md5_shards->contains(data.md5s, md5);
+  return binary_search(data.md5s.cbegin(), data.md5s.cend(), md5);
+}
+
+/** Whether the --sha1-md5-map pairs sha1 with an MD5 that's present. */
+bool maps_to_present_md5(const HashData& data, const sha1_digest& sha1) {
+  auto iter = std::lower_bound(
+      data.sha1_md5s.cbegin(), data.sha1_md5s.cend(), sha1,
+      [](const std::pair<sha1_digest, pair64>& entry,
+         const sha1_digest& key) { return entry.first < key; });
+  for (; iter != data.sha1_md5s.cend() && iter->first == sha1; ++iter)
+    if (contains_md5(data, iter->second)) return true;
+  return false;
+}
+}  // namespace
+
+/** How many threads to spread loading work across. */
+unsigned worker_count() {
+  return std::max(1u, std::thread::hardware_concurrency());
+}
+
+/** Throws a LoadError for a file that just failed to open, classified
+ * by errno.  what names the file, e.g. "index /srv/nsrl.idx".
+ */
+[[noreturn]] void open_failed(const string& what) {
+  using Kind = LoadError::Kind;
+  const auto error = errno;
+  const auto kind = ENOENT == error   ? Kind::NotFound
+                    : EACCES == error ? Kind::PermissionDenied
+                                      : Kind::Unreadable;
+  throw LoadError(kind, "couldn't open " + what + ": " + std::strerror(error));
+}
+
+/** Attempts to load a set of MD5, SHA-1 and SHA-256 hashes from every
+ * one of files, which may be text files or RDSv3 SQLite databases.  The
+ * hashes are merged and deduplicated once they're all in.  If any
+ * file can't be loaded, or memory or threads run out along the way, it
+ * throws a LoadError saying why, leaving sets incomplete.
+ *
+ * The files were checked when the options were parsed, but one can
+ * still vanish or change permissions before it's read here -- all the
+ * more so on a SIGHUP reload.
+ */
+void load_hashes(HashSets& sets, const vector<string>& files,
+                 const LoadOptions& options) {
+  using Kind = LoadError::Kind;
+  // Sorting and merging allocate and start threads of their own, and
+  // a failure there is as much a failed load as a bad file.
+  try {
+    read_hashes(sets, files, options);
+  } catch (std::bad_alloc&) {
+    throw LoadError(Kind::OutOfMemory, "couldn't allocate enough memory");
+  } catch (std::system_error& e) {
+    throw LoadError(Kind::Unreadable,
+                    string("couldn't start a thread to load with: ") +
+                        e.what());
+  }
+}
+
+/** Moves freshly-loaded hashes into a HashData. */
+std::shared_ptr<HashData> make_hash_data(
+    HashSets& sets, std::unique_ptr<BloomFilter>& filter,
+    std::unique_ptr<CompactSet>& compact) {
+  std::shared_ptr<HashData> fresh;
+  try {
+    fresh = std::make_shared<HashData>();
+  } catch (std::bad_alloc&) {
+    throw LoadError(LoadError::Kind::OutOfMemory,
+                    "couldn't allocate enough memory");
+  }
+  fresh->md5_store.swap(sets.md5s);
+  fresh->sha1s.swap(sets.sha1s);
+  fresh->sha256s.swap(sets.sha256s);
+  fresh->md5_sources.swap(sets.md5_sources);
+  fresh->sha1_sources.swap(sets.sha1_sources);
+  fresh->sha256_sources.swap(sets.sha256_sources);
+  fresh->sources.swap(sets.sources);
+  fresh->compact_md5s.swap(compact);
+  fresh->filter.swap(filter);
+  fresh->md5s = HashView<pair64>(
+      fresh->md5_store.data(),
+      fresh->md5_store.data() + fresh->md5_store.size());
+  return fresh;
+}
+
+/** Whether hash is in data.  Hashes are compared as the binary digests
+ * they spell out, never as text, so lower, upper and mixed case hex all
+ * match alike.
+ * @throws std::invalid_argument if hash isn't a well-formed MD5, SHA-1
+ * or SHA-256
+ */
+bool contains_hash(const HashData& data, const string& hash) {
+  switch (hash.size()) {
+    case 40: {
+      // A SHA-1 that isn't loaded itself may still be answered for by
+      // its MD5.
+      const auto sha1 = to_sha1(hash);
+      const bool found = filter_only(data)
+                             ? data.filter->contains(bloom_key(sha1))
+                             : binary_search(data.sha1s.cbegin(),
+                                             data.sha1s.cend(), sha1);
+      return found || maps_to_present_md5(data, sha1);
+    }
+    case 64:
+      if (filter_only(data))
+        return data.filter->contains(bloom_key(to_sha256(hash)));
+      return binary_search(data.sha256s.cbegin(), data.sha256s.cend(),
+                           to_sha256(hash));
+    default:
+      return contains_md5(data, to_pair64(hash));
+  }
+}
+
+HashData::~HashData() {
+  if (nullptr != mapping) munmap(mapping, mapping_length);
+}
+
+HashStore::HashStore(std::shared_ptr<const HashData> hashes)
+    : data{std::move(hashes)} {}
+
+HashStore HashStore::load(const string& path) {
+  HashSets sets;
+  std::unique_ptr<BloomFilter> filter;
+  std::unique_ptr<CompactSet> compact;
+  load_hashes(sets, {path}, LoadOptions{});
+  return HashStore(make_hash_data(sets, filter, compact));
+}
+
+namespace {
+/** The hashes HashStore::from_hashes() makes a store of, without its
+ * std::bad_alloc and std::system_error turned into LoadErrors.
+ */
+std::shared_ptr<HashData> data_of(const vector<string>& hashes) {
+  HashSets sets;
+  for (const auto& hash : hashes) {
+    switch (hash.size()) {
+      case 32:
+        sets.md5s.push_back(to_pair64(hash));
+        break;
+      case 40:
+        sets.sha1s.push_back(to_sha1(hash));
+        break;
+      case 64:
+        sets.sha256s.push_back(to_sha256(hash));
+        break;
+      default:
+        throw invalid_argument("not a hash");
+    }
+  }
+  const LoadOptions quiet;
+  sort_and_dedup(sets.md5s, quiet);
+  sort_and_dedup(sets.sha1s, quiet);
+  sort_and_dedup(sets.sha256s, quiet);
+  std::unique_ptr<BloomFilter> filter;
+  std::unique_ptr<CompactSet> compact;
+  return make_hash_data(sets, filter, compact);
+}
+}  // namespace
+
+HashStore HashStore::from_hashes(const vector<string>& hashes) {
+  try {
+    return HashStore(data_of(hashes));
+  } catch (std::bad_alloc&) {
+    throw LoadError(LoadError::Kind::OutOfMemory,
+                    "couldn't allocate enough memory");
+  } catch (std::system_error& e) {
+    throw LoadError(LoadError::Kind::Unreadable,
+                    string("couldn't start a thread to load with: ") +
+                        e.what());
+  }
+}
+
+bool HashStore::contains(const string& hash) const {
+  try {
+    return contains_hash(*data, hash);
+  } catch (invalid_argument&) {
+    return false;
+  }
+}
+
+vector<bool> HashStore::query(const vector<string>& hashes) const {
+  vector<bool> rv;
+  rv.reserve(hashes.size());
+  for (const auto& hash : hashes) rv.push_back(contains(hash));
+  return rv;
+}
+
+uint64_t HashStore::size() const { return data->size(); }
@@ -0,0 +1,151 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <sys/socket.h>
+#include <sys/time.h>
+#include <algorithm>
+#include <iostream>
+#include <sstream>
+#include <string>
+#include "main.h"
+
+using boost::asio::ip::tcp;
+using std::getline;
+using std::string;
+using std::stringstream;
+
+namespace {
+/** The most request body the built-in endpoints will accept. */
+const size_t max_body_bytes{16 * 1024 * 1024};
+
+const char* status_text(const int status) {
+  switch (status) {
+    case 200:
+      return "OK";
+    case 400:
+      return "Bad Request";
+    case 404:
+      return "Not Found";
+    case 405:
+      return "Method Not Allowed";
+    case 413:
+      return "Payload Too Large";
+    case 503:
+      return "Service Unavailable";
+    default:
+      return "Internal Server Error";
+  }
+}
+
+/** Strips the carriage return getline() leaves on a CRLF line. */
+void chomp(string& line) {
+  if (!line.empty() && '\r' == line.back()) line.pop_back();
+}
+
+/** Reads a request line, its headers and, if Content-Length says there
+ * is one, its body.
+ * @return 0 on success, or the status to refuse the request with
+ */
+int read_request(std::iostream& stream, HttpRequest& request) {
+  string line;
+  if (!getline(stream, line)) return 400;
+  chomp(line);
+  string target;
+  stringstream(line) >> request.method >> target;
+  if (request.method.empty() || target.empty()) return 400;
+  const auto question = target.find('?');
+  request.path = target.substr(0, question);
+  if (string::npos != question) request.query = target.substr(question + 1);
+
+  size_t length{0};
+  while (getline(stream, line)) {
+    chomp(line);
+    if (line.empty()) break;
+    const auto colon = line.find(':');
+    if (string::npos == colon) continue;
+    string name = line.substr(0, colon);
+    std::transform(name.begin(), name.end(), name.begin(), ::tolower);
+    if ("content-length" != name) continue;
+    try {
+      length = std::stoul(line.substr(colon + 1));
+    } catch (std::exception&) {
+      return 400;
+    }
+  }
+  if (!stream) return 400;
+  if (length > max_body_bytes) return 413;
+
+  request.body.resize(length);
+  if (0 < length && !stream.read(&request.body[0], length)) return 400;
+  return 0;
+}
+}  // namespace
+
+HttpResponse::HttpResponse(const int status, const string& content_type,
+                           const string& body)
+    : status{status}, content_type{content_type}, body{body} {}
+
+/** Reads one HTTP request from stream and writes back what answer
+ * makes of it, or why it was refused.
+ */
+void answer_http(std::iostream& stream,
+                 HttpResponse (*answer)(const HttpRequest&)) {
+  HttpRequest request;
+  const auto refusal = read_request(stream, request);
+  const auto response =
+      refusal ? HttpResponse(refusal, "text/plain", status_text(refusal))
+              : answer(request);
+  stream << "HTTP/1.0 " << response.status << " "
+         << status_text(response.status) << "\r\n"
+         << "Content-Type: " << response.content_type << "\r\n"
+         << "Content-Length: " << response.body.size() << "\r\n"
+         << "Connection: close\r\n\r\n"
+         << response.body << std::flush;
+}
+
+/** Answers HTTP requests on acceptor, one at a time, for as long as the
+ * server runs.  Every connection is closed after a single response.
+ */
+void serve_http(boost::asio::io_service& io_service, tcp::acceptor& acceptor,
+                HttpResponse (*answer)(const HttpRequest&)) {
+  AcceptBackoff backoff{"port " +
+                        std::to_string(acceptor.local_endpoint().port())};
+  for (;;) {
+    tcp::socket socket(io_service);
+    boost::system::error_code error;
+    acceptor.accept(socket, error);
+    if (error) {
+      backoff.failed(error);
+      continue;
+    }
+    backoff.succeeded();
+    const auto peer = socket.remote_endpoint(error);
+    if (error || !is_allowed_client(peer.address())) {
+      socket.close(error);
+      continue;
+    }
+
+    // A client that stalls mustn't wedge the endpoint for everyone.
+    timeval limit{5, 0};
+    setsockopt(socket.native_handle(), SOL_SOCKET, SO_RCVTIMEO, &limit,
+               sizeof(limit));
+
+    PlainSocketBuffer buffer(socket.native_handle());
+    std::iostream stream(&buffer);
+    answer_http(stream, answer);
+    socket.close(error);
+  }
+}
@@ -0,0 +1,126 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include "main.h"
+
+#ifdef HAVE_HTTP
+
+#include <sstream>
+#include <string>
+#include <vector>
+
+using boost::asio::ip::tcp;
+using std::string;
+using std::stringstream;
+using std::vector;
+
+namespace {
+/** Finds the hash named in a query string such as "md5=ABC..".  The
+ * sha1 and sha256 parameters work too; the hash's length is what
+ * decides how it's looked up, as with QUERY.
+ */
+bool hash_parameter(const string& query, string& hash) {
+  stringstream params(query);
+  string param;
+  while (std::getline(params, param, '&')) {
+    const auto equals = param.find('=');
+    if (string::npos == equals) continue;
+    const auto name = param.substr(0, equals);
+    if (name == "md5" || name == "sha1" || name == "sha256") {
+      hash = param.substr(equals + 1);
+      return !hash.empty();
+    }
+  }
+  return false;
+}
+
+void skip_space(const string& text, size_t& pos) {
+  while (pos < text.size() && (' ' == text[pos] || '\t' == text[pos] ||
+                               '\r' == text[pos] || '\n' == text[pos]))
+    ++pos;
+}
+
+/** Parses a JSON array of strings.  Hashes never need escapes, so a
+ * backslash anywhere is treated as malformed input.
+ */
+bool parse_string_array(const string& text, vector<string>& values) {
+  size_t pos{0};
+  skip_space(text, pos);
+  if (pos >= text.size() || '[' != text[pos++]) return false;
+  skip_space(text, pos);
+  if (pos < text.size() && ']' == text[pos]) {
+    ++pos;
+  } else {
+    for (;;) {
+      skip_space(text, pos);
+      if (pos >= text.size() || '"' != text[pos++]) return false;
+      const auto close = text.find_first_of("\"\\", pos);
+      if (string::npos == close || '"' != text[close]) return false;
+      values.push_back(text.substr(pos, close - pos));
+      pos = close + 1;
+      skip_space(text, pos);
+      if (pos >= text.size()) return false;
+      if (']' == text[pos]) {
+        ++pos;
+        break;
+      }
+      if (',' != text[pos++]) return false;
+    }
+  }
+  skip_space(text, pos);
+  return pos == text.size();
+}
+
+HttpResponse answer_lookup(const HttpRequest& request) {
+  if (request.path != "/lookup")
+    return HttpResponse(404, "text/plain", "not found\n");
+
+  if (request.method == "GET") {
+    vector<string> hash(1);
+    if (!hash_parameter(request.query, hash[0]))
+      return HttpResponse(400, "text/plain", "expected ?md5=<hash>\n");
+    const auto found = lookup_hashes(hash.cbegin(), hash.cend()).at(0);
+    return HttpResponse(200, "application/json",
+                        found ? "{\"present\":true}\n"
+                              : "{\"present\":false}\n");
+  }
+
+  if (request.method == "POST") {
+    vector<string> hashes;
+    if (!parse_string_array(request.body, hashes))
+      return HttpResponse(400, "text/plain",
+                          "expected a JSON array of hashes\n");
+    const auto found = lookup_hashes(hashes.cbegin(), hashes.cend());
+    string body{"["};
+    for (size_t idx = 0; idx < found.size(); ++idx) {
+      if (idx) body += ",";
+      body += found[idx] ? "true" : "false";
+    }
+    body += "]\n";
+    return HttpResponse(200, "application/json", body);
+  }
+
+  return HttpResponse(405, "text/plain", "GET or POST only\n");
+}
+}  // namespace
+
+/** Answers lookups over HTTP on acceptor for as long as the server runs. */
+void serve_lookups(boost::asio::io_service& io_service,
+                   tcp::acceptor& acceptor) {
+  serve_http(io_service, acceptor, answer_lookup);
+}
+
+#endif
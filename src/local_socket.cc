@@ -0,0 +1,81 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+
+#include <sys/socket.h>
+#include <sys/stat.h>
+#include <sys/un.h>
+#include <unistd.h>
+#include <boost/asio.hpp>
+#include <cstring>
+#include <memory>
+#include <stdexcept>
+#include <string>
+#include "main.h"
+
+using boost::asio::local::stream_protocol;
+using std::runtime_error;
+using std::string;
+using std::unique_ptr;
+
+namespace {
+/** Whether a process is accepting connections on the socket at path. */
+bool is_listening(const string& path) {
+  const int fd = socket(AF_UNIX, SOCK_STREAM, 0);
+  if (0 > fd) return false;
+  sockaddr_un address;
+  std::memset(&address, 0, sizeof(address));
+  address.sun_family = AF_UNIX;
+  path.copy(address.sun_path, sizeof(address.sun_path) - 1);
+  const auto rv = 0 == connect(fd, reinterpret_cast<sockaddr*>(&address),
+                               sizeof(address));
+  close(fd);
+  return rv;
+}
+}  // namespace
+
+/** Listens on a Unix socket at path, which only gets the permissions
+ * in mode, with room for backlog connections to wait for an accept().
+ * A socket left behind by an earlier run is replaced.  One
+ * that a live process is still listening on, or anything that isn't a
+ * socket, is left alone and this throws a runtime_error.
+ */
+unique_ptr<stream_protocol::acceptor> open_unix_socket(
+    boost::asio::io_service& io_service, const string& path,
+    const mode_t mode, const unsigned int backlog) {
+  struct stat info;
+  if (0 == lstat(path.c_str(), &info) && S_ISSOCK(info.st_mode)) {
+    if (is_listening(path))
+      throw runtime_error(path + " is already in use by another process");
+    unlink(path.c_str());
+  }
+  // Setting the mode after the bind would leave a window where anyone
+  // could connect.
+  const auto saved = umask(~mode & 0777);
+  try {
+    unique_ptr<stream_protocol::acceptor> rv{
+        new stream_protocol::acceptor(io_service)};
+    const stream_protocol::endpoint endpoint(path);
+    rv->open(endpoint.protocol());
+    rv->bind(endpoint);
+    rv->listen(static_cast<int>(backlog));
+    umask(saved);
+    return rv;
+  } catch (...) {
+    umask(saved);
+    throw;
+  }
+}
@@ -17,28 +17,51 @@ OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
 #include "main.h"
 #include <errno.h>
 #include <limits.h>
+#include <netinet/in.h>
+#include <netinet/tcp.h>
+#include <poll.h>
+#include <pwd.h>
+#include <fcntl.h>
+#include <grp.h>
 #include <signal.h>
+#include <sys/mman.h>
+#include <sys/socket.h>
 #include <sys/stat.h>
+#include <sys/time.h>
+#include <sys/wait.h>
 #include <time.h>
 #include <unistd.h>
 #include <algorithm>
+#include <atomic>
 #include <boost/asio.hpp>
 #include <boost/program_options.hpp>
+#include <cctype>
+#include <chrono>
+#include <condition_variable>
 #include <cstdio>
 #include <cstdlib>
 #include <cstring>
+#include <deque>
 #include <exception>
 #include <fstream>
+#include <functional>
+#include <future>
+#include <iomanip>
 #include <iostream>
+#include <map>
+#include <memory>
+#include <mutex>
+#include <random>
 #include <regex>
+#include <set>
+#include <sstream>
+#include <stdexcept>
+#include <thread>
 #include <vector>
 
 using boost::asio::ip::tcp;
 using boost::program_options::notify;
 using boost::program_options::options_description;
-using boost::program_options::parse_command_line;
-using boost::program_options::store;
-using boost::program_options::value;
 using boost::program_options::variables_map;
 using std::cerr;
 using std::cout;
@@ -49,110 +72,1214 @@ using std::pair;
 using std::regex;
 using std::sort;
 using std::stoi;
+using std::chrono::steady_clock;
 using std::string;
 using std::to_string;
 using std::transform;
 using std::vector;
 
 namespace {
-vector<pair64> hash_set;
-string hashes_location{PKGDATADIR "/hashes.txt"};
+// What nsrlsvr exits with, so that a script can tell why it stopped;
+// nsrlsvr(1) lists them.  Bad options exit with EXIT_FAILURE, which is
+// 1, and --check has its own.
+const int exit_load_failed{2};
+const int exit_listen_failed{3};
+const int exit_start_failed{4};
+const int exit_verify_failed{5};
+
+vector<string> hash_files{PKGDATADIR "/hashes.txt"};
+// Without --file, says where the hashes are being read from.
+string default_file_note;
+// From --dataset: the hash files behind each named dataset.
+std::map<string, vector<string>> dataset_files;
 uint16_t port{9120};
+uint16_t metrics_port{0};
+uint16_t http_port{0};
+string admin_socket_path;
+string unix_socket_path;
+bool drop_privileges{false};
+string pid_file;
+// Applied to the PID file, log file and index nsrlsvr creates, and,
+// if --umask was given, to the --unix-socket.
+mode_t file_mask{022};
+bool mask_unix_socket{false};
+string log_path;
+FILE* log_file{nullptr};
+std::mutex log_mutex;
+uid_t run_as_uid{0};
+gid_t run_as_gid{0};
+boost::asio::ip::address bind_address;
+bool dual_stack{true};
+// From --allow; with neither set, every client is let in.
+vector<boost::asio::ip::network_v4> allowed_v4;
+vector<boost::asio::ip::network_v6> allowed_v6;
+#ifdef HAVE_OPENSSL
+std::unique_ptr<boost::asio::ssl::context> tls_context;
+#endif
+// Only kept to be named in the startup banner.
+string tls_cert_path;
+string tls_key_path;
 bool dry_run{false};
+bool log_to_stderr{false};
+// Messages less urgent than this are dropped.
+int log_threshold{LOG_INFO};
+bool log_json{false};
+string index_location;
+// How --madvise says the mapped index will be read.
+int index_advice{MADV_RANDOM};
+string index_output;
+string sha1_md5_map_path;
+bool use_bloom{false};
+// With --bloom and --index, whether hits are checked against the index.
+bool bloom_verify{false};
+bool use_compact{false};
+// 0 means the MD5s are searched as one sorted array.
+unsigned int shard_bits{0};
+bool track_sources{false};
+bool allow_nearest{false};
+bool rds_sha1{false};
+bool invert{false};
+bool allow_empty{false};
+uint64_t expected_hashes{0};
+double expect_tolerance{0};
+bool strict{false};
+char hit_mark{'1'};
+char miss_mark{'0'};
+bool bench{false};
+uint64_t bench_count{1000000};
+bool verify{false};
+// From --sample; if empty, --verify samples the loaded hashes instead.
+vector<string> sample_hashes;
+double bloom_fpr{1e-6};
+uint64_t progress_interval{1000000};
+// 0 means the first load may take as long as it likes.
+unsigned int load_timeout{0};
+bool watch{false};
+unsigned int watch_settle{5};
+// What queries are answered from.  dataset_mutex only guards the
+// pointer itself, so it's never held for longer than a copy or swap.
+std::shared_ptr<const HashData> dataset{std::make_shared<HashData>()};
+std::map<string, std::shared_ptr<const HashData>> named_datasets;
+std::mutex dataset_mutex;
+std::unique_ptr<RateLimiter> limiter;
+std::unique_ptr<QueryCache> cache;
+// Set before main() runs, so the threads that report uptime never see
+// it change.
+const steady_clock::time_point start{steady_clock::now()};
+volatile sig_atomic_t stopping{0};
+volatile sig_atomic_t listen_fd{-1};
+volatile sig_atomic_t reloading{0};
+volatile sig_atomic_t stats_requested{0};
+std::atomic<unsigned int> sessions{0};
+std::atomic<ServerState> state{ServerState::Loading};
+unsigned int max_connections{256};
+unsigned int idle_timeout{300};
+unsigned int max_hashes{10000};
+size_t max_line{1048576};
+unsigned int session_workers{1};
+unsigned int accept_queue{0};
+unsigned int listen_backlog{1024};
+// What --loading-behavior does with clients that connect before the
+// first load is done.
+enum class LoadingBehavior {
+  Refuse,  // don't listen until then
+  Reject,  // answer NOT OK loading and hang up
+  Hold     // leave them in the listen backlog until then
+};
+LoadingBehavior loading_behavior{LoadingBehavior::Refuse};
+unsigned int tcp_keepalive{0};
+
+// TCP and Unix-socket clients wait in the same queue.
+using client_socket = boost::asio::generic::stream_protocol::socket;
+
+/** One of the --max-connections slots, held from the moment a client
+ * is queued until its session is over, however it ends: served in
+ * full, hung up on at shutdown, or cut short by an exception.  A slot
+ * that's been moved from holds nothing.
+ */
+class SessionSlot {
+ public:
+  SessionSlot() { ++sessions; }
+  SessionSlot(SessionSlot&& other) noexcept : held{other.held} {
+    other.held = false;
+  }
+  SessionSlot(const SessionSlot&) = delete;
+  SessionSlot& operator=(const SessionSlot&) = delete;
+  SessionSlot& operator=(SessionSlot&&) = delete;
+  ~SessionSlot() {
+    if (held) --sessions;
+  }
+
+ private:
+  bool held{true};
+};
+
+/** A connection that's been accepted but not yet picked up by a worker. */
+struct PendingClient {
+  client_socket socket;
+  string ipaddr;
+  bool local;
+  SessionSlot slot;
+};
+
+std::mutex queue_mutex;
+std::condition_variable queue_ready;
+std::deque<PendingClient> pending;
+bool queue_closed{false};
+
+/** Hands on what load_hashes() has to say to log(). */
+void log_loading(const LogLevel level, const string& msg) {
+  log(level, string(msg));
+}
+
+/** How load_hashes() should read the hash files, as the options say. */
+LoadOptions load_options() {
+  LoadOptions rv;
+  rv.rds_sha1 = rds_sha1;
+  rv.progress_interval = progress_interval;
+  rv.track_sources = track_sources;
+  rv.log = log_loading;
+  return rv;
+}
+
+/** Picks up to n MD5s at random from [first, last), as hex, for
+ * --bench or --verify to look up as hashes known to be present.
+ */
+vector<string> sample_md5s(const pair64* first, const pair64* last,
+                           const size_t n) {
+  vector<string> rv;
+  if (first == last) return rv;
+  std::mt19937_64 rng{std::random_device{}()};
+  std::uniform_int_distribution<std::ptrdiff_t> pick{0, last - first - 1};
+  rv.reserve(n);
+  while (rv.size() < n) rv.push_back(from_pair64(first[pick(rng)]));
+  return rv;
+}
 
-/** Attempts to load a set of MD5 hashes from disk.
- * Each line must be either blank or 32 hexadecimal digits.  If the
- * file doesn't conform to this, nsrlsvr will abort and display an
- * error message to the log.
- */
-void load_hashes() {
-  const regex md5_re{"^[A-Fa-f0-9]{32}$"};
-  uint32_t hash_count{0};
-  ifstream infile{hashes_location.c_str()};
-
-  // As of this writing, the full RDS had about 81 million entries.
-  // When a vector needs to grow, it normally does so by doubling
-  // the former allocation -- so after this, the next stop is a
-  // 200 million allocation (@ 16 bytes per, or 3.2 GB).  If you're
-  // maintaining this code, try to keep the reserve a few million
-  // larger than the RDS currently is, to give yourself room to
-  // grow without a vector realloc.
-  //
-  // Failure to reserve this block of memory is non-recoverable.
-  // Don't even try.  Just log the error and bail out.  Let the end
-  // user worry about installing more RAM.
+/** Replaces the sorted hash sets with a single Bloom filter over all
+ * of them.  The sets are freed afterwards, which is the whole point:
+ * at the default false-positive rate the filter needs about a fifth of
+ * the memory an MD5 set does.
+ */
+void build_bloom_filter(HashSets& sets, std::unique_ptr<BloomFilter>& filter) {
   try {
-    hash_set.reserve(100000000);
+    filter.reset(new BloomFilter(sets.size(), bloom_fpr));
   } catch (std::bad_alloc&) {
-    log(LogLevel::ALERT, "couldn't reserve enough memory");
-    exit(EXIT_FAILURE);
+    throw LoadError(LoadError::Kind::OutOfMemory,
+                    "couldn't allocate enough memory for Bloom filter");
   }
 
-  if (not infile) {
-    log(LogLevel::ALERT, "couldn't open hashes file " + hashes_location);
-    exit(EXIT_FAILURE);
+  for (const auto& hash : sets.md5s) filter->insert(bloom_key(hash));
+  for (const auto& hash : sets.sha1s) filter->insert(bloom_key(hash));
+  for (const auto& hash : sets.sha256s) filter->insert(bloom_key(hash));
+
+  vector<pair64>().swap(sets.md5s);
+  vector<sha1_digest>().swap(sets.sha1s);
+  vector<sha256_digest>().swap(sets.sha256s);
+
+  log(LogLevel::INFO, "built Bloom filter of " + to_string(filter->bytes()) +
+                          " bytes using " +
+                          to_string(filter->hash_functions()) +
+                          " hash functions");
+}
+
+/** For --bloom-verify: puts a Bloom filter in front of a mapped index.
+ * Most misses are then answered without touching the index, and a hit
+ * is only reported once the index confirms it, so there are no false
+ * positives.
+ */
+void build_index_filter(HashData& data) {
+  try {
+    data.filter.reset(new BloomFilter(data.md5s.size(), bloom_fpr));
+  } catch (std::bad_alloc&) {
+    throw LoadError(LoadError::Kind::OutOfMemory,
+                    "couldn't allocate enough memory for Bloom filter");
   }
+  for (auto iter = data.md5s.cbegin(); iter != data.md5s.cend(); ++iter)
+    data.filter->insert(bloom_key(*iter));
+  log(LogLevel::INFO, "built Bloom filter of " +
+                          to_string(data.filter->bytes()) +
+                          " bytes to check before the index");
+}
 
-  while (infile) {
-    string line;
-    getline(infile, line);
-    transform(line.begin(), line.end(), line.begin(), ::toupper);
-    if (0 == line.size()) continue;
+/** Moves the MD5s into a CompactSet, freeing the sorted vector. */
+void build_compact_set(HashSets& sets,
+                       std::unique_ptr<CompactSet>& compact) {
+  try {
+    compact.reset(new CompactSet(sets.md5s));
+  } catch (std::bad_alloc&) {
+    throw LoadError(LoadError::Kind::OutOfMemory,
+                    "couldn't allocate enough memory for the compact set");
+  } catch (std::length_error& e) {
+    throw LoadError(LoadError::Kind::OutOfMemory, e.what());
+  }
+  const auto plain = sets.md5s.size() * sizeof(pair64);
+  vector<pair64>().swap(sets.md5s);
 
-    if (!regex_match(line.cbegin(), line.cend(), md5_re)) {
-      log(LogLevel::ALERT, "hash file appears corrupt!  Loading no hashes.");
-      log(LogLevel::ALERT, "offending line is: " + line);
-      log(LogLevel::ALERT, "shutting down!");
-      exit(EXIT_FAILURE);
-    }
+  log(LogLevel::INFO, "packed " + to_string(compact->size()) +
+                          " MD5s into " + to_string(compact->bytes()) +
+                          " bytes, down from " + to_string(plain));
+}
+
+/** Builds a --shard-bits table over data's MD5s. */
+void shard_md5s(HashData& data) {
+  try {
+    data.md5_shards.reset(new ShardTable(data.md5s, shard_bits));
+  } catch (std::bad_alloc&) {
+    throw LoadError(LoadError::Kind::OutOfMemory,
+                    "couldn't allocate enough memory for the shard table");
+  }
+  log(LogLevel::INFO, "split " + to_string(data.md5s.size()) + " MD5s into " +
+                          to_string(1ULL << shard_bits) + " shards, using " +
+                          to_string(data.md5_shards->bytes()) + " bytes");
+}
 
+/** Logs why loading failed, along with what's likely to fix it. */
+void report_load_error(const LoadError& error) {
+  log(LogLevel::ALERT, error.what());
+  switch (error.kind()) {
+    case LoadError::Kind::NotFound:
+      if (!index_location.empty())
+        log(LogLevel::ALERT, "check the --index setting");
+      else
+        log(LogLevel::ALERT, "check the --file setting, or run nsrlupdate "
+                             "to build a hash file");
+      break;
+    case LoadError::Kind::PermissionDenied:
+      log(LogLevel::ALERT, "make sure the account nsrlsvr runs as can read "
+                           "it");
+      break;
+    case LoadError::Kind::Corrupt:
+      if (!index_location.empty())
+        log(LogLevel::ALERT, "rebuild the index with --build-index");
+      else
+        log(LogLevel::ALERT, "each line must be blank or an MD5, SHA-1 or "
+                             "SHA-256 in hex; rerun nsrlupdate to rebuild "
+                             "it");
+      break;
+    case LoadError::Kind::OutOfMemory:
+      log(LogLevel::ALERT, "free up memory, or try --bloom, --compact or "
+                           "--index to need less of it");
+      break;
+    case LoadError::Kind::Unreadable:
+      break;
+  }
+}
+
+/** Reads the --sha1-md5-map into map, sorted by SHA-1.  Each line
+ * starts with a SHA-1 and an MD5 in hex, separated by a comma and
+ * perhaps in double quotes; anything after them is ignored, so an RDS
+ * NSRLFile.txt does as is.  Blank lines are skipped, as is a first line
+ * that doesn't start with a SHA-1, taken for a header.  Throws a
+ * LoadError if the file can't be read or a line doesn't conform.
+ */
+void load_sha1_md5_map(vector<pair<sha1_digest, pair64>>& map) {
+  using Kind = LoadError::Kind;
+  std::ifstream infile{sha1_md5_map_path.c_str()};
+  if (!infile) open_failed("SHA-1 to MD5 map " + sha1_md5_map_path);
+
+  // Takes the next comma-separated field from line, starting at pos.
+  const auto next_field = [](const string& line, size_t& pos) {
+    if (pos >= line.size()) return string();
+    const auto comma = std::min(line.find(',', pos), line.size());
+    auto field = line.substr(pos, comma - pos);
+    pos = comma + 1;
+    const auto first = field.find_first_not_of(" \t\r\"");
+    const auto last = field.find_last_not_of(" \t\r\"");
+    return string::npos == first ? string()
+                                 : field.substr(first, last - first + 1);
+  };
+
+  vector<pair<sha1_digest, pair64>> rv;
+  string line;
+  uint64_t line_count{0};
+  uint64_t bad_lines{0};
+  uint64_t first_bad{0};
+  while (getline(infile, line)) {
+    ++line_count;
+    if (string::npos == line.find_first_not_of(" \t\r")) continue;
+    size_t pos{0};
+    const auto sha1 = next_field(line, pos);
+    const auto md5 = next_field(line, pos);
     try {
-      // .emplace_back is the C++11 improvement over the old
-      // vector.push_back.  It has the benefit of not needing
-      // to construct a temporary to hold the value; it can
-      // just construct-in-place.  For 40 million values, that
-      // can be significant.
-      //
-      // Note that if the vector runs out of reserved room it
-      // will attempt to make a new allocation double the size
-      // of the last.  That means the application will at least
-      // briefly need *three times* the expected RAM -- one for
-      // the data set and two for the newly-allocated chunk.
-      // Given we're talking about multiple gigs of RAM, this
-      // .emplace_back needs to consider the possibility of a
-      // RAM allocation failure.
-      hash_set.emplace_back(to_pair64(line));
-      hash_count += 1;
-      if (0 == hash_count % 1000000) {
-        string howmany{to_string(hash_count / 1000000)};
-        log(LogLevel::INFO, "loaded " + howmany + " million hashes");
+      if (40 == sha1.size() && 32 == md5.size()) {
+        rv.emplace_back(to_sha1(sha1), to_pair64(md5));
+        continue;
       }
-    } catch (std::bad_alloc&) {
-      log(LogLevel::ALERT, "couldn't allocate enough memory");
-      exit(EXIT_FAILURE);
+    } catch (std::invalid_argument&) {
+      // Not hex; counted as a bad line below.
     }
+    if (1 == line_count) continue;
+    if (0 == bad_lines++) first_bad = line_count;
   }
-  string howmany{to_string(hash_count)};
-  log(LogLevel::INFO, "read in " + howmany + " hashes");
+  if (infile.bad())
+    throw LoadError(Kind::Unreadable,
+                    "couldn't finish reading " + sha1_md5_map_path);
+  if (0 < bad_lines)
+    throw LoadError(Kind::Corrupt,
+                    sha1_md5_map_path + " has " + to_string(bad_lines) +
+                        " lines that aren't a SHA-1 and an MD5, the first "
+                        "being line " +
+                        to_string(first_bad));
 
-  infile.close();
+  sort(rv.begin(), rv.end());
+  rv.erase(std::unique(rv.begin(), rv.end()), rv.end());
+  log(LogLevel::INFO, "read in " + to_string(rv.size()) +
+                          " SHA-1 to MD5 mappings from " +
+                          sha1_md5_map_path);
+  map.swap(rv);
+}
 
-  sort(hash_set.begin(), hash_set.end());
+/** Makes fresh the data queries are answered from.  Lookups already
+ * under way finish against the old data, which is freed once the last
+ * of them lets go of it; the swap itself never waits on them.
+ */
+void publish(std::shared_ptr<const HashData> fresh) {
+  {
+    std::lock_guard<std::mutex> lock(dataset_mutex);
+    dataset.swap(fresh);
+  }
+  // fresh now holds the old data, and may be the last reference to it:
+  // let it go out here rather than under the lock.
+}
 
-  if (hash_set.size() > 1) {
-    log(LogLevel::INFO, "ensuring no duplicates");
-    for (auto iter = (hash_set.cbegin() + 1); iter != hash_set.cend(); ++iter) {
-      if (*(iter - 1) == *iter) {
-        log(LogLevel::ALERT,
-	    string("Line #") + std::to_string(iter - hash_set.cbegin()) +
-            "hash file contains duplicates -- "
-            "shutting down!");
-        exit(EXIT_FAILURE);
-      }
+/** Loads every --dataset, each the same way as the main hash files
+ * but for --index.  Throws a LoadError if any of them can't be.
+ */
+std::map<string, std::shared_ptr<const HashData>> load_named_datasets() {
+  std::map<string, std::shared_ptr<const HashData>> rv;
+  for (const auto& named : dataset_files) {
+    log(LogLevel::INFO, "loading dataset " + named.first);
+    HashSets sets;
+    std::unique_ptr<BloomFilter> filter;
+    std::unique_ptr<CompactSet> compact;
+    load_hashes(sets, named.second, load_options());
+    if (use_bloom) build_bloom_filter(sets, filter);
+    if (use_compact) build_compact_set(sets, compact);
+    auto fresh = make_hash_data(sets, filter, compact);
+    if (0 != shard_bits) shard_md5s(*fresh);
+    rv[named.first] = fresh;
+  }
+  return rv;
+}
+
+/** Makes fresh the named datasets queries can be answered from. */
+void publish_named(std::map<string, std::shared_ptr<const HashData>> fresh) {
+  {
+    std::lock_guard<std::mutex> lock(dataset_mutex);
+    named_datasets.swap(fresh);
+  }
+}
+
+/** What an index file starts with.  At 24 bytes it keeps the records
+ * that follow 8-byte aligned in the mapping.
+ */
+struct IndexHeader {
+  char magic[8];
+  uint32_t version;
+  uint32_t record_size;
+  uint64_t count;
+};
+static_assert(sizeof(IndexHeader) == 24, "IndexHeader must be 24 bytes");
+
+const char index_magic[8] = {'N', 'S', 'R', 'L', 'I', 'D', 'X', '\0'};
+// Bump this whenever the layout changes, so old indexes get ignored
+// instead of misread.
+const uint32_t index_version{1};
+
+/** Writes the loaded MD5s to disk as a header followed by a packed,
+ * sorted array of pair64 records, which map_index() can later use
+ * without any parsing or sorting.  Everything is in this machine's byte
+ * order, so an index should be rebuilt rather than copied between
+ * architectures; one that is copied fails the version check.
+ * @return whether the whole index was written
+ */
+bool write_index() {
+  const auto data = current_hashes();
+  IndexHeader header;
+  std::copy(index_magic, index_magic + sizeof(index_magic), header.magic);
+  header.version = index_version;
+  header.record_size = sizeof(pair64);
+  header.count = data->md5s.size();
+  std::ofstream outfile{index_output.c_str(), std::ios::binary};
+  outfile.write(reinterpret_cast<const char*>(&header), sizeof(header));
+  outfile.write(
+      reinterpret_cast<const char*>(data->md5s.cbegin()),
+      static_cast<std::streamsize>(data->md5s.size() * sizeof(pair64)));
+  outfile.close();
+  if (not outfile) {
+    log(LogLevel::ALERT, "couldn't write index " + index_output);
+    return false;
+  }
+  if (data->sha1s.size() || data->sha256s.size())
+    log(LogLevel::WARN, "indexes hold MD5s only; SHA-1 and SHA-256 hashes "
+                        "were left out");
+  log(LogLevel::INFO, "wrote " + to_string(data->md5s.size()) +
+                          " hashes to " + index_output);
+  return true;
+}
+
+/** Maps a prebuilt index read-only into memory, for MD5s to be served
+ * straight out of.  Since the pages are clean and file-backed, the
+ * kernel is free to drop them under memory pressure and fault them back
+ * in later.  The mapping goes once nothing's using it.  On failure this
+ * throws a LoadError.
+ * @return null if the file isn't an index this version of nsrlsvr can
+ * read, so the hash files should be loaded instead
+ */
+std::shared_ptr<HashData> map_index() {
+  using Kind = LoadError::Kind;
+  const int fd = open(index_location.c_str(), O_RDONLY);
+  if (0 > fd) open_failed("index " + index_location);
+  struct stat info;
+  if (0 > fstat(fd, &info)) {
+    close(fd);
+    throw LoadError(Kind::Unreadable, "couldn't read index " + index_location);
+  }
+  IndexHeader header;
+  const auto length = static_cast<size_t>(info.st_size);
+  if (!S_ISREG(info.st_mode) || length < sizeof(header) ||
+      static_cast<ssize_t>(sizeof(header)) !=
+          pread(fd, &header, sizeof(header), 0) ||
+      !std::equal(index_magic, index_magic + sizeof(index_magic),
+                  header.magic) ||
+      index_version != header.version ||
+      sizeof(pair64) != header.record_size) {
+    close(fd);
+    log(LogLevel::WARN, index_location + " isn't an index this version of "
+                        "nsrlsvr can read; loading the hash files instead");
+    return nullptr;
+  }
+  const auto body = length - sizeof(header);
+  if (0 != body % sizeof(pair64) || header.count != body / sizeof(pair64)) {
+    close(fd);
+    throw LoadError(Kind::Corrupt, "index " + index_location +
+                                       " is the wrong size for its " +
+                                       to_string(header.count) + " hashes");
+  }
+
+  void* base = mmap(nullptr, length, PROT_READ, MAP_SHARED, fd, 0);
+  if (MAP_FAILED == base) {
+    const auto kind = ENOMEM == errno ? Kind::OutOfMemory : Kind::Unreadable;
+    close(fd);
+    throw LoadError(kind, "couldn't map index " + index_location);
+  }
+  close(fd);
+
+  auto fresh = std::make_shared<HashData>();
+  fresh->mapping = base;
+  fresh->mapping_length = length;
+  // The check below reads the whole index in order, so readahead helps
+  // there, whatever the queries afterwards want.
+  madvise(base, length, MADV_SEQUENTIAL);
+  const auto records = reinterpret_cast<const pair64*>(
+      static_cast<const char*>(base) + sizeof(header));
+  // Lookups binary-search the records, so one out of order would make
+  // hashes that are there come back as misses, with nothing to say
+  // why.  One pass to rule that out is cheap next to a day's queries.
+  const auto last = records + header.count;
+  const auto unsorted =
+      std::adjacent_find(records, last, [](const pair64& lhs,
+                                           const pair64& rhs) {
+        return !(lhs < rhs);
+      });
+  if (last != unsorted)
+    throw LoadError(Kind::Corrupt,
+                    "index " + index_location + " isn't sorted: record " +
+                        to_string(unsorted - records + 1) +
+                        " isn't less than the next");
+  if (0 != madvise(base, length, index_advice))
+    log(LogLevel::WARN, string("couldn't pass on --madvise for ") +
+                            index_location + ": " + std::strerror(errno));
+  fresh->md5s = HashView<pair64>(records, last);
+  log(LogLevel::INFO, "mapped " + to_string(fresh->md5s.size()) +
+                          " hashes from " + index_location);
+  return fresh;
+}
+
+/** Checks a hash count against --expect-hashes, logging an error if
+ * it's further off than --expect-tolerance allows.  That's the usual
+ * sign of a truncated or half-downloaded dataset.
+ * @return whether the count is close enough, or nothing was expected
+ */
+bool loaded_expected_count(const uint64_t loaded) {
+  if (0 == expected_hashes) return true;
+  const auto difference = loaded > expected_hashes ? loaded - expected_hashes
+                                                   : expected_hashes - loaded;
+  if (100.0 * static_cast<double>(difference) <=
+      expect_tolerance * static_cast<double>(expected_hashes))
+    return true;
+  log(LogLevel::ALERT, "loaded " + to_string(loaded) + " hashes, but " +
+                           to_string(expected_hashes) +
+                           " were expected; is the dataset complete?");
+  return false;
+}
+
+/** Logs how much RAM the process holds once hashes are loaded, so an
+ * operator can see what a dataset really costs.  For a mapped index
+ * this only counts the pages touched so far.
+ */
+void log_memory_usage() {
+  const auto rss = resident_bytes();
+  if (0 == rss) {
+    log(LogLevel::DEBUG, "couldn't find out resident memory usage");
+    return;
+  }
+  log(LogLevel::INFO, "resident memory is now " +
+                          to_string((rss + 524288) / 1048576) + " MiB");
+}
+
+/** Maps the --index, or failing that loads the hash files, along with
+ * any --sha1-md5-map, into data that isn't being served yet.  Throws a
+ * LoadError if that can't be done.
+ * @param known_hashes if not null, filled with MD5s for --bench or
+ * --verify to look up
+ */
+std::shared_ptr<HashData> load_main_data(vector<string>* known_hashes) {
+  // --bench and --verify need known MD5s, which have to be picked
+  // before a Bloom filter throws the hash sets away.
+  const size_t samples =
+      verify ? 100 : std::min<uint64_t>(bench_count / 2, 100000);
+  std::shared_ptr<HashData> fresh;
+  if (!index_location.empty()) fresh = map_index();
+  if (fresh) {
+    if (known_hashes)
+      *known_hashes = sample_md5s(fresh->md5s.cbegin(), fresh->md5s.cend(),
+                                  samples);
+    if (bloom_verify) build_index_filter(*fresh);
+  } else {
+    HashSets sets;
+    std::unique_ptr<BloomFilter> filter;
+    std::unique_ptr<CompactSet> compact;
+    load_hashes(sets, hash_files, load_options());
+    if (known_hashes)
+      *known_hashes = sample_md5s(sets.md5s.data(),
+                                  sets.md5s.data() + sets.md5s.size(),
+                                  samples);
+    if (use_bloom) build_bloom_filter(sets, filter);
+    if (use_compact) build_compact_set(sets, compact);
+    fresh = make_hash_data(sets, filter, compact);
+  }
+  if (0 != shard_bits) shard_md5s(*fresh);
+  if (!sha1_md5_map_path.empty()) load_sha1_md5_map(fresh->sha1_md5s);
+  return fresh;
+}
+
+/** Whether reloaded data is fit to replace what's being served: not
+ * empty, unless --allow-empty, and within --expect-hashes.  Says why
+ * if it isn't.
+ */
+bool fit_to_serve(const HashData& fresh) {
+  if (0 == fresh.size() && !allow_empty) {
+    log(LogLevel::ALERT, "the reload found no hashes; check that the hash "
+                         "files are the right ones, or pass --allow-empty");
+    return false;
+  }
+  return loaded_expected_count(fresh.size());
+}
+
+/** Loads the hash file (or index) afresh and, if that works and the
+ * result passes fit_to_serve(), serves it in place of the current data.
+ * If not, the current data stays.  Sessions already in progress pick up
+ * the new data with their next command.
+ */
+void reload_hashes() {
+  log(LogLevel::INFO, "reloading hashes");
+  bool reloaded{false};
+  try {
+    // Everything is loaded and checked before anything is published,
+    // so that if any of it fails none of it changes.
+    auto named = load_named_datasets();
+    auto fresh = load_main_data(nullptr);
+    if (fit_to_serve(*fresh)) {
+      publish(std::move(fresh));
+      publish_named(std::move(named));
+      reloaded = true;
+    }
+  } catch (LoadError& e) {
+    report_load_error(e);
+  } catch (std::bad_alloc&) {
+    report_load_error(LoadError(LoadError::Kind::OutOfMemory,
+                                "couldn't allocate enough memory"));
+  } catch (std::exception& e) {
+    // Whatever went wrong, the server was fine before the reload and
+    // still is.
+    log(LogLevel::ALERT, string("the reload failed: ") + e.what());
+  }
+
+  if (reloaded) {
+    state = ServerState::Ready;
+    log(LogLevel::INFO, "reload complete");
+    log_memory_usage();
+  } else {
+    state = ServerState::Degraded;
+    log(LogLevel::ALERT, "reload failed; still serving the previous hashes");
+  }
+}
+
+/** SIGTERM/SIGINT handler.  Besides noting that we've been asked to
+ * stop, it shuts down the listening socket (if this process has one)
+ * so an accept() already in progress returns immediately.
+ */
+void request_stop(int) {
+  stopping = 1;
+  if (0 <= listen_fd) shutdown(listen_fd, SHUT_RDWR);
+}
+
+/** SIGHUP handler.  The accept loop starts the reload. */
+void request_reload(int) { reloading = 1; }
+
+/** SIGUSR1 handler.  The accept loop does the logging. */
+void request_stats(int) { stats_requested = 1; }
+
+/** Logs a one-line snapshot of the same figures STATUS and the metrics
+ * endpoint report.
+ */
+void log_statistics() {
+  const uint64_t looked_up = metrics.hashes_queried;
+  const uint64_t hits = metrics.hits;
+  std::ostringstream ratio;
+  ratio << std::fixed << std::setprecision(1)
+        << (0 < looked_up ? 100.0 * hits / looked_up : 0.0);
+  const auto uptime = std::chrono::duration_cast<std::chrono::seconds>(
+      steady_clock::now() - start);
+  log(LogLevel::INFO,
+      to_string(loaded_hash_count()) + " hashes loaded, " +
+          to_string(metrics.queries) + " queries answered, " +
+          to_string(looked_up) + " hashes looked up, " + ratio.str() +
+          "% hits, " + to_string(sessions) + " clients connected, up " +
+          to_string(uptime.count()) + "s");
+}
+
+
+/** Installs a signal handler.
+    @param signum the signal to handle
+    @param handler the function to call
+    @param restart whether interrupted system calls should be restarted
+*/
+void install_handler(const int signum, void (*handler)(int),
+                     const bool restart) {
+  struct sigaction action;
+  std::memset(&action, 0, sizeof(action));
+  action.sa_handler = handler;
+  sigemptyset(&action.sa_mask);
+  action.sa_flags = restart ? SA_RESTART : 0;
+  sigaction(signum, &action, nullptr);
+}
+
+/** The signals the accept loop handles.  Every other thread starts
+    with these blocked, so they always land on the loop and interrupt
+    its poll(). */
+sigset_t loop_signals() {
+  sigset_t rv;
+  sigemptyset(&rv);
+  sigaddset(&rv, SIGTERM);
+  sigaddset(&rv, SIGINT);
+  sigaddset(&rv, SIGHUP);
+  sigaddset(&rv, SIGUSR1);
+  return rv;
+}
+
+/** Installs request_stop() for SIGTERM and SIGINT.
+    @param restart whether interrupted system calls should be restarted
+*/
+void install_stop_handler(const bool restart) {
+  install_handler(SIGTERM, request_stop, restart);
+  install_handler(SIGINT, request_stop, restart);
+}
+
+/** What --watch keeps an eye on for each file: a change to any of
+ * these means it's been rewritten or replaced.
+ */
+struct FileStamp {
+  bool exists;
+  dev_t device;
+  ino_t inode;
+  off_t size;
+  time_t modified;
+
+  bool operator==(const FileStamp& rhs) const {
+    return exists == rhs.exists && device == rhs.device &&
+           inode == rhs.inode && size == rhs.size &&
+           modified == rhs.modified;
+  }
+  bool operator!=(const FileStamp& rhs) const { return !(*this == rhs); }
+};
+
+/** Polls the files the hashes come from for --watch.  A file that
+ * nsrlupdate or cp is still writing keeps changing, so a reload waits
+ * until nothing has changed for --watch-settle seconds; a truncated
+ * file never gets loaded just because it was caught mid-write.
+ */
+class HashFileWatch {
+ public:
+  HashFileWatch() : loaded{stamps()}, seen{loaded} {}
+
+  /** Whether the files have changed since they were last loaded and
+   * have since settled.  Once it says so it takes the files as
+   * reloaded, whether or not the reload works: a file that won't
+   * load isn't retried until it changes again.
+   */
+  bool settled_change() {
+    // poll() wakes the main loop for every client, which is no reason
+    // to stat() everything again.
+    const auto now = steady_clock::now();
+    if (now < next_check) return false;
+    next_check = now + std::chrono::seconds(1);
+    const auto current = stamps();
+    if (current != seen) {
+      seen = current;
+      changed_at = now;
+      return false;
+    }
+    if (current == loaded ||
+        now - changed_at < std::chrono::seconds(watch_settle))
+      return false;
+    for (const auto& stamp : current)
+      if (!stamp.exists) return false;
+    loaded = current;
+    return true;
+  }
+
+  /** Takes the files as they are now to be the ones loaded, as when
+   * SIGHUP reloads them.
+   */
+  void reloading() { loaded = seen = stamps(); }
+
+ private:
+  static vector<string> watched() {
+    vector<string> rv;
+    if (!index_location.empty())
+      rv.push_back(index_location);
+    else
+      rv = hash_files;
+    for (const auto& named : dataset_files)
+      rv.insert(rv.end(), named.second.cbegin(), named.second.cend());
+    return rv;
+  }
+
+  static vector<FileStamp> stamps() {
+    vector<FileStamp> rv;
+    for (const auto& path : watched()) {
+      struct stat info;
+      if (0 != stat(path.c_str(), &info))
+        rv.push_back(FileStamp{false, 0, 0, 0, 0});
+      else
+        rv.push_back(FileStamp{true, info.st_dev, info.st_ino, info.st_size,
+                               info.st_mtime});
+    }
+    return rv;
+  }
+
+  vector<FileStamp> loaded;
+  vector<FileStamp> seen;
+  steady_clock::time_point changed_at{steady_clock::now()};
+  steady_clock::time_point next_check{steady_clock::now()};
+};
+
+// The sockets of sessions in progress, so that shutdown can hang up on
+// clients that are sitting idle rather than wait for them to speak.
+std::mutex live_mutex;
+std::set<int> live_sockets;
+// How far hang_up_sessions() has gone: 0, SHUT_RD or SHUT_RDWR + 1.
+int hung_up{0};
+
+/** Keeps a session's socket in live_sockets for as long as the session
+ * lasts.  It has to go before the socket is closed, so that shutdown
+ * never touches a descriptor that's since been reused.
+ */
+class LiveSocket {
+ public:
+  explicit LiveSocket(const int fd) : fd{fd} {
+    std::lock_guard<std::mutex> lock(live_mutex);
+    live_sockets.insert(fd);
+    // A session that started just as we began shutting down mustn't be
+    // missed.
+    if (0 < hung_up) shutdown(fd, hung_up - 1);
+  }
+  LiveSocket(const LiveSocket&) = delete;
+  LiveSocket& operator=(const LiveSocket&) = delete;
+  ~LiveSocket() {
+    std::lock_guard<std::mutex> lock(live_mutex);
+    live_sockets.erase(fd);
+  }
+
+ private:
+  const int fd;
+};
+
+/** Shuts down every live session's socket.  SHUT_RD ends a session at
+ * its next read, once the command in progress has been answered;
+ * SHUT_RDWR also breaks off a reply the client isn't reading.
+ */
+void hang_up_sessions(const int how) {
+  std::lock_guard<std::mutex> lock(live_mutex);
+  hung_up = how + 1;
+  for (const auto fd : live_sockets) shutdown(fd, how);
+}
+
+/** Waits up to a deadline for the sessions still in progress to end.
+ * @return false if some are still going
+ */
+bool sessions_ended(const steady_clock::time_point deadline) {
+  while (0 != sessions) {
+    if (steady_clock::now() >= deadline) return false;
+    usleep(100000);
+  }
+  return true;
+}
+
+/** Gives sessions still in progress a few seconds to finish the
+ * command they're on, then hangs up on any that haven't.  Idle clients
+ * are hung up on at once, since their sessions are blocked reading.
+ * @return false if some sessions are still going even so
+ */
+bool drain_sessions() {
+  hang_up_sessions(SHUT_RD);
+  if (sessions_ended(steady_clock::now() + std::chrono::seconds(5)))
+    return true;
+  log(LogLevel::WARN, "clients still connected at shutdown; hanging up");
+  hang_up_sessions(SHUT_RDWR);
+  return sessions_ended(steady_clock::now() + std::chrono::seconds(1));
+}
+
+/** Runs one client's session over TLS if it's configured, or in the
+    clear otherwise, and closes the connection afterwards.  This runs
+    on whichever worker picked the connection up.  Clients on the
+    --unix-socket are on this machine, so they're always served in the
+    clear. */
+void serve_client(client_socket socket, const string& ipaddr,
+                  const bool local, SessionSlot slot) {
+  // Only here to be given back when this returns or throws.
+  (void)slot;
+  const int fd = socket.native_handle();
+  if (0 < idle_timeout) {
+    timeval limit{static_cast<time_t>(idle_timeout), 0};
+    setsockopt(fd, SOL_SOCKET, SO_RCVTIMEO, &limit, sizeof(limit));
+  }
+
+  bool idle{false};
+  {
+    // Over before the socket is closed below.
+    const LiveSocket live{fd};
+#ifdef HAVE_OPENSSL
+    if (tls_context && !local)
+      idle = handle_tls_client(*tls_context, fd, ipaddr);
+    else
+#else
+    (void)local;
+#endif
+      idle = handle_plain_client(fd, ipaddr);
+  }
+  if (idle)
+    log(LogLevel::DEBUG, ipaddr + " was idle for " +
+                             to_string(idle_timeout) + "s; disconnected");
+
+  boost::system::error_code ignored;
+  socket.close(ignored);
+}
+
+/** Serves queued connections one after another until the queue is
+    closed.  There are session_workers of these. */
+void run_worker() {
+  for (;;) {
+    std::unique_lock<std::mutex> lock(queue_mutex);
+    queue_ready.wait(lock, [] { return queue_closed || !pending.empty(); });
+    if (queue_closed) return;
+    auto client = std::move(pending.front());
+    pending.pop_front();
+    lock.unlock();
+    // Whatever goes wrong with one session, the worker carries on
+    // with the next, and the slot goes back as the stack unwinds.
+    try {
+      serve_client(std::move(client.socket), client.ipaddr, client.local,
+                   std::move(client.slot));
+    } catch (std::exception& e) {
+      log(LogLevel::WARN, "session with " + client.ipaddr +
+                              " ended with an error: " + e.what());
+    }
+  }
+}
+
+/** Hands an accepted connection to the next free worker.
+    @return false, leaving socket alone, if --accept-queue clients are
+    already waiting for one */
+bool enqueue_client(client_socket& socket, const string& ipaddr,
+                    const bool local) {
+  {
+    std::lock_guard<std::mutex> lock(queue_mutex);
+    if (0 < accept_queue && pending.size() >= accept_queue) return false;
+    pending.push_back(
+        PendingClient{std::move(socket), ipaddr, local, SessionSlot()});
+  }
+  queue_ready.notify_one();
+  return true;
+}
+
+/** Sets up a freshly accepted TCP connection.  Replies are small and
+    a client waits on each before sending more, so Nagle's algorithm
+    would only add delay.  With --tcp-keepalive, an idle connection is
+    probed so that NAT and firewalls along the way don't forget it. */
+void tune_socket(tcp::socket& socket) {
+  boost::system::error_code ignored;
+  socket.set_option(tcp::no_delay(true), ignored);
+  if (0 == tcp_keepalive) return;
+  const int fd = socket.native_handle();
+  const int on{1};
+  setsockopt(fd, SOL_SOCKET, SO_KEEPALIVE, &on, sizeof(on));
+#ifdef TCP_KEEPIDLE
+  const int idle = static_cast<int>(tcp_keepalive);
+  setsockopt(fd, IPPROTO_TCP, TCP_KEEPIDLE, &idle, sizeof(idle));
+  setsockopt(fd, IPPROTO_TCP, TCP_KEEPINTVL, &idle, sizeof(idle));
+#endif
+}
+
+/** Queues an accepted connection for a worker, or turns it away with
+    BUSY if --max-connections clients are already connected or the
+    --accept-queue is full. */
+void admit_client(client_socket socket, const string& ipaddr,
+                  const bool local) {
+  log(LogLevel::ALERT, string("accepted a client: ") + ipaddr);
+  const char* refusal{nullptr};
+  if (sessions >= max_connections) {
+    refusal = "too many connections";
+  } else if (enqueue_client(socket, ipaddr, local)) {
+    ++metrics.connections;
+  } else {
+    refusal = "every worker is busy and the accept queue is full";
+  }
+  if (nullptr == refusal) return;
+  log(LogLevel::WARN, "turned away " + ipaddr + ": " + refusal);
+  boost::system::error_code error;
+  boost::asio::write(socket, boost::asio::buffer("BUSY\r\n", 6), error);
+}
+
+/** Stops the workers once their current sessions end, hanging up on
+    any clients still waiting for one. */
+void close_queue() {
+  {
+    std::lock_guard<std::mutex> lock(queue_mutex);
+    pending.clear();
+    queue_closed = true;
+  }
+  queue_ready.notify_all();
+}
+
+/** Adds one --allow range.  A bare address allows just that address.
+ * @return false if text isn't an IPv4 or IPv6 address or range
+ */
+bool add_allowed_range(string text) {
+  using namespace boost::asio::ip;
+  const auto is_v6 = string::npos != text.find(':');
+  if (string::npos == text.find('/')) text += is_v6 ? "/128" : "/32";
+  boost::system::error_code error;
+  if (is_v6) {
+    const auto range = make_network_v6(text, error);
+    if (!error) allowed_v6.push_back(range.canonical());
+  } else {
+    const auto range = make_network_v4(text, error);
+    if (!error) allowed_v4.push_back(range.canonical());
+  }
+  return !error;
+}
+
+/** Opens, binds and starts listening on the --bind-address at the
+    given port, honouring --dual-stack for IPv6 addresses.
+    @return false, having logged why, if the port can't be had */
+bool start_listening(tcp::acceptor& acceptor, const uint16_t listen_port) {
+  const tcp::endpoint endpoint(bind_address, listen_port);
+  boost::system::error_code error;
+  acceptor.open(endpoint.protocol(), error);
+  if (!error && endpoint.protocol() == tcp::v6()) {
+    // Not every platform lets IPV6_V6ONLY be cleared; if it can't be,
+    // we still serve IPv6 and just don't pick up IPv4 clients.
+    boost::system::error_code ec;
+    acceptor.set_option(boost::asio::ip::v6_only(!dual_stack), ec);
+    if (ec && dual_stack)
+      log(LogLevel::WARN, "could not enable dual-stack listening: " +
+                              ec.message());
+  }
+  if (!error) acceptor.bind(endpoint, error);
+  if (!error) acceptor.listen(static_cast<int>(listen_backlog), error);
+  if (error)
+    log(LogLevel::ALERT, "couldn't listen on port " + to_string(listen_port) +
+                             ": " + error.message());
+  return !error;
+}
+
+/** Logs the listen backlog in effect.  Linux quietly caps it at
+ * net.core.somaxconn, so --backlog alone may overstate it.
+ */
+void log_backlog() {
+  std::ifstream limit{"/proc/sys/net/core/somaxconn"};
+  unsigned int somaxconn{0};
+  if (limit >> somaxconn && somaxconn < listen_backlog)
+    log(LogLevel::WARN, "the kernel caps the listen backlog at " +
+                            to_string(somaxconn) + ", below --backlog's " +
+                            to_string(listen_backlog) +
+                            "; raise net.core.somaxconn to allow more");
+  else
+    log(LogLevel::INFO, "listen backlog is " + to_string(listen_backlog));
+}
+
+/** Answers each client that connects to acceptor with NOT OK loading
+    and hangs up, until loading is cleared.  For --loading-behavior
+    reject. */
+void reject_while_loading(boost::asio::io_service& io,
+                          tcp::acceptor& acceptor,
+                          const std::atomic<bool>& loading) {
+  AcceptBackoff backoff{"port " + to_string(port)};
+  while (loading) {
+    // Woken now and then to notice that the load is over.
+    pollfd waiting{acceptor.native_handle(), POLLIN, 0};
+    if (0 >= poll(&waiting, 1, 100)) continue;
+    boost::system::error_code error;
+    tcp::socket socket(io);
+    acceptor.accept(socket, error);
+    if (error) {
+      backoff.failed(error);
+      continue;
+    }
+    backoff.succeeded();
+    const auto peer = socket.remote_endpoint(error);
+    if (error || !is_allowed_client(peer.address())) continue;
+    log(LogLevel::INFO, "turned away " + peer.address().to_string() +
+                            ": still loading");
+    boost::asio::write(socket, boost::asio::buffer("NOT OK loading\r\n", 16),
+                       error);
+  }
+}
+
+/** The --bind-address, bracketed if it's IPv6 so a port can follow. */
+string listen_address() {
+  return bind_address.is_v6() ? "[" + bind_address.to_string() + "]"
+                              : bind_address.to_string();
+}
+
+/** A one-line summary of how the server is set up, logged once it's
+ * listening so that a log shows what a problem happened under.  Key
+ * files are named, never read out.
+ */
+string startup_banner() {
+  const auto join = [](const vector<string>& items, const string& gap) {
+    string rv;
+    for (const auto& item : items) rv += (rv.empty() ? "" : gap) + item;
+    return rv;
+  };
+  vector<string> features;
+  if (!tls_cert_path.empty())
+    features.push_back("TLS (cert " + tls_cert_path + ", key " +
+                       tls_key_path + ")");
+  if (0 != metrics_port)
+    features.push_back("metrics on port " + to_string(metrics_port));
+  if (0 != http_port)
+    features.push_back("HTTP lookups on port " + to_string(http_port));
+  if (!unix_socket_path.empty())
+    features.push_back("Unix socket " + unix_socket_path);
+  if (!admin_socket_path.empty())
+    features.push_back("admin socket " + admin_socket_path);
+  if (use_bloom)
+    features.push_back(bloom_verify ? "Bloom filter checked against the index"
+                                    : "Bloom filter");
+  if (use_compact) features.push_back("compact set");
+  if (0 != shard_bits)
+    features.push_back(to_string(1ULL << shard_bits) + " MD5 shards");
+  if (track_sources) features.push_back("source tracking");
+  if (!sha1_md5_map_path.empty())
+    features.push_back("SHA-1 to MD5 map " + sha1_md5_map_path);
+  if (!dataset_files.empty())
+    features.push_back("datasets " + join(dataset_names(), " "));
+  if (limiter) features.push_back("rate limiting");
+  if (cache) features.push_back("query cache");
+  if (watch) features.push_back("watching hash files");
+
+  std::ostringstream rv;
+  rv << "nsrlsvr " << PACKAGE_VERSION << " serving " << loaded_hash_count()
+     << " hashes from "
+     << (index_location.empty() ? join(hash_files, " ")
+                                : "index " + index_location)
+     << " on " << listen_address() << ":" << port << " with "
+     << session_workers << " workers for up to " << max_connections
+     << " clients; " << (features.empty() ? "no extras" : join(features, ", "));
+  return rv.str();
+}
+
+/** For --dry-run: says what a real run would have served, and where,
+ * without binding anything.
+ */
+void report_dry_run() {
+  const auto address = listen_address();
+  cout << "loaded " << loaded_hash_count() << " hashes\n"
+       << "would listen on " << address << ":" << port
+#ifdef HAVE_OPENSSL
+       << (tls_context ? " (TLS)" : "")
+#endif
+       << "\n";
+  if (0 != metrics_port)
+    cout << "would serve metrics on " << address << ":" << metrics_port
+         << "\n";
+  if (0 != http_port)
+    cout << "would answer HTTP lookups on " << address << ":" << http_port
+         << "\n";
+  if (!unix_socket_path.empty())
+    cout << "would listen on " << unix_socket_path << "\n";
+  if (!admin_socket_path.empty())
+    cout << "would take admin commands on " << admin_socket_path << "\n";
+}
+
+/** Gives up root for the --user and --group accounts.  Called once
+    the listeners are bound, since only root can bind a port below
+    1024.  Any failure is fatal: carrying on as root isn't an option. */
+void become_unprivileged() {
+  if (0 != setgroups(1, &run_as_gid) || 0 != setgid(run_as_gid) ||
+      0 != setuid(run_as_uid)) {
+    log(LogLevel::ALERT, string("couldn't drop privileges: ") +
+                             std::strerror(errno));
+    exit(exit_start_failed);
+  }
+  // If root can be regained, the drop didn't take.
+  if (0 != run_as_uid && 0 == setuid(0)) {
+    log(LogLevel::ALERT, "still able to regain root; refusing to run");
+    exit(exit_start_failed);
+  }
+  log(LogLevel::INFO, "now running as uid " + to_string(run_as_uid) +
+                          ", gid " + to_string(run_as_gid));
+}
+
+/** Removes the --pid-file.  Registered with atexit() and
+    at_quick_exit() once the file's been written. */
+void remove_pid_file() {
+  if (0 != unlink(pid_file.c_str()) && ENOENT != errno)
+    log(LogLevel::WARN, "couldn't remove PID file " + pid_file + ": " +
+                            std::strerror(errno));
+}
+
+/** Writes our PID to --pid-file, replacing a stale one.  If the file
+    names a process that's still running, another nsrlsvr probably
+    owns it, so we stop rather than clobber it. */
+void write_pid_file() {
+  ifstream existing{pid_file.c_str()};
+  long old_pid{0};
+  if (existing >> old_pid) {
+    if (0 < old_pid && getpid() != old_pid &&
+        0 == kill(static_cast<pid_t>(old_pid), 0)) {
+      log(LogLevel::ALERT, pid_file + " belongs to running process " +
+                               to_string(old_pid) + "; shutting down!");
+      exit(exit_start_failed);
     }
+    log(LogLevel::WARN, "replacing stale PID file " + pid_file);
   }
+  existing.close();
 
-  log(LogLevel::INFO, "successfully loaded hashes");
+  std::ofstream outfile{pid_file.c_str(), std::ios::trunc};
+  outfile << getpid() << "\n";
+  outfile.close();
+  if (not outfile) {
+    log(LogLevel::ALERT, "couldn't write PID file " + pid_file);
+    exit(exit_start_failed);
+  }
+  atexit(remove_pid_file);
+  at_quick_exit(remove_pid_file);
 }
 
 /** Converts this process into a well-behaved UNIX daemon.*/
@@ -163,22 +1290,22 @@ void daemonize() {
   const auto pid = fork();
   if (0 > pid) {
     log(LogLevel::WARN, "couldn't fork!");
-    exit(EXIT_FAILURE);
+    exit(exit_start_failed);
   } else if (0 < pid) {
     exit(EXIT_SUCCESS);
   }
   log(LogLevel::INFO, "daemon started");
 
-  umask(0);
+  umask(file_mask);
 
   if (0 > setsid()) {
     log(LogLevel::WARN, "couldn't set sid");
-    exit(EXIT_FAILURE);
+    exit(exit_start_failed);
   }
 
   if (0 > chdir("/")) {
     log(LogLevel::WARN, "couldn't chdir to root");
-    exit(EXIT_FAILURE);
+    exit(exit_start_failed);
   }
 
   close(STDIN_FILENO);
@@ -186,43 +1313,106 @@ void daemonize() {
   close(STDERR_FILENO);
 }
 
-/** Parse command-line options.
-    @param argc argc from main()
-    @param argv argv from main()
+/** Anchors a relative path to the current directory, for files that
+ * needn't exist yet.  daemonize() changes directory to /, so this has
+ * to happen first.
+ */
+string absolute_path(const string& path) {
+  if ('/' == path[0]) return path;
+  std::array<char, PATH_MAX> cwd;
+  if (nullptr == getcwd(cwd.data(), cwd.size())) {
+    cerr << "Couldn't find the current directory.\n";
+    exit(exit_start_failed);
+  }
+  return string(cwd.data()) + "/" + path;
+}
+
+const char* level_name(const LogLevel level) {
+  switch (level) {
+    case LogLevel::WARN:
+      return "WARN";
+    case LogLevel::DEBUG:
+      return "DEBUG";
+    case LogLevel::CRITICAL:
+      return "CRITICAL";
+    case LogLevel::ALERT:
+      return "ALERT";
+    case LogLevel::EMERGENCY:
+      return "EMERGENCY";
+    case LogLevel::INFO:
+      break;
+  }
+  return "INFO";
+}
+
+/** Reads a --log-level name, as level_name() writes it.
+ * @return false if name isn't one
+ */
+bool parse_level(const string& name, LogLevel& level) {
+  string upper;
+  transform(name.cbegin(), name.cend(), std::back_inserter(upper), ::toupper);
+  for (const auto candidate :
+       {LogLevel::EMERGENCY, LogLevel::ALERT, LogLevel::CRITICAL,
+        LogLevel::WARN, LogLevel::INFO, LogLevel::DEBUG})
+    if (upper == level_name(candidate)) {
+      level = candidate;
+      return true;
+    }
+  return false;
+}
+
+/** Opens (or reopens) the --log-file for appending.  Lines are flushed
+ * as they're written, so nothing is lost if the process dies.
+ * @return whether the file could be opened; if not, the old one stays
+ */
+bool open_log_file() {
+  FILE* fresh = fopen(log_path.c_str(), "a");
+  if (nullptr == fresh) return false;
+  setvbuf(fresh, nullptr, _IOLBF, 0);
+  std::lock_guard<std::mutex> lock(log_mutex);
+  if (nullptr != log_file) fclose(log_file);
+  log_file = fresh;
+  return true;
+}
+
+/** Where to look for hashes without a --file, in order: the
+ * compiled-in default, then the other places they're usually put.
+ */
+vector<string> default_file_candidates() {
+  vector<string> rv{PKGDATADIR "/hashes.txt"};
+  const char* data_home = getenv("XDG_DATA_HOME");
+  const char* home = getenv("HOME");
+  // An unset or empty XDG_DATA_HOME means ~/.local/share.
+  if (nullptr != data_home && '\0' != *data_home)
+    rv.push_back(string(data_home) + "/nsrlsvr/hashes.txt");
+  else if (nullptr != home && '\0' != *home)
+    rv.push_back(string(home) + "/.local/share/nsrlsvr/hashes.txt");
+  rv.push_back("/var/lib/nsrlsvr/hashes.txt");
+  rv.push_back("./hashes.txt");
+  return rv;
+}
+
+/** The first of default_file_candidates() that exists.  If none do,
+ * the compiled-in default, so that it's the one reported missing.
+ */
+string default_hash_file() {
+  const auto candidates = default_file_candidates();
+  for (const auto& path : candidates)
+    if (0 == access(path.c_str(), F_OK)) return path;
+  return candidates.front();
+}
+
+/** Turns a user-supplied path into an absolute one, making sure the
+    file it names can be read.  Any problem is fatal.
+    @param relpath the path as given on the command line
+    @param failure what to exit with if it can't be read: the default
+    for the hashes and what answers for them, 1 for anything else
 */
-void parse_options(int argc, char* argv[]) {
+string resolve_path(const string& relpath,
+                    const int failure = exit_load_failed) {
   std::array<char, PATH_MAX> filename_buffer;
   char* filepath{&filename_buffer[0]};
   fill(filename_buffer.begin(), filename_buffer.end(), 0);
-  options_description options{"nsrlsvr options"};
-  options.add_options()("help,h", "Help screen")("version,v",
-                                                 "Display package version")(
-      "bug-report,b", "Display bug reporting information")(
-      "file,f", value<string>()->default_value(PKGDATADIR "/hashes.txt"),
-      "hash file")("port,p", value<uint16_t>()->default_value(9120), "port")(
-      "dry-run", "test configuration");
-  variables_map vm;
-  store(parse_command_line(argc, argv, options), vm);
-
-  dry_run = vm.count("dry-run") ? true : false;
-
-  if (vm.count("help")) {
-    cout << options << "\n";
-    exit(EXIT_SUCCESS);
-  }
-  if (vm.count("version")) {
-    cout << "nsrlsvr version " << PACKAGE_VERSION
-         << "\n\n"
-            "This program is released under the ISC License.\n";
-    exit(EXIT_SUCCESS);
-  }
-  if (vm.count("bug-report")) {
-    cout << "To file a bug report, visit "
-            "https://github.com/rjhansen/nsrlsvr/issues\n";
-    exit(EXIT_SUCCESS);
-  }
-  port = vm["port"].as<uint16_t>();
-  string relpath = vm["file"].as<string>();
   if (nullptr == (filepath = realpath(relpath.c_str(), filepath))) {
     switch (errno) {
       case EACCES:
@@ -259,18 +1449,519 @@ void parse_options(int argc, char* argv[]) {
         cerr << "... wtfbbq?  This should never trip.  It's an nsrlsvr bug.\n";
         break;
     }
+    exit(failure);
+  }
+  const string abspath{filepath};
+  if (not ifstream(abspath.c_str())) {
+    cerr << "Could not open " + abspath + " for reading.\n";
+    exit(failure);
+  }
+  return abspath;
+}
+
+/** Parse command-line options.
+    @param argc argc from main()
+    @param argv argv from main()
+*/
+void parse_options(int argc, char* argv[]) {
+  options_description options;
+  options.add(command_line_options()).add(setting_options());
+  variables_map vm;
+  try {
+    read_options(argc, argv, vm);
+  } catch (std::runtime_error& e) {
+    cerr << e.what() << "\n";
+    exit(EXIT_FAILURE);
+  }
+
+  dry_run = vm["dry-run"].as<bool>();
+
+  if (vm.count("help")) {
+    cout << options << "\n";
+    exit(EXIT_SUCCESS);
+  }
+  if (vm.count("version")) {
+    cout << "nsrlsvr version " << PACKAGE_VERSION
+         << "\n\n"
+            "This program is released under the ISC License.\n";
+    exit(EXIT_SUCCESS);
+  }
+  if (vm.count("bug-report")) {
+    cout << "To file a bug report, visit "
+            "https://github.com/rjhansen/nsrlsvr/issues\n";
+    exit(EXIT_SUCCESS);
+  }
+  if (!vm["check"].as<bool>() && (vm.count("input") || vm.count("output"))) {
+    cerr << "--input and --output only go with --check.\n";
+    exit(EXIT_FAILURE);
+  }
+  if (vm["check"].as<bool>())
+    exit(run_check(vm["host"].as<string>(), vm["port"].as<uint16_t>(),
+                   vm.count("input") ? vm["input"].as<string>() : "",
+                   vm.count("output") ? vm["output"].as<string>() : ""));
+  port = vm["port"].as<uint16_t>();
+  metrics_port = vm["metrics-port"].as<uint16_t>();
+  if (0 != metrics_port && metrics_port == port) {
+    cerr << "--metrics-port can't be the same as --port.\n";
+    exit(EXIT_FAILURE);
+  }
+  http_port = vm["http-port"].as<uint16_t>();
+  if (vm.count("admin-socket"))
+    admin_socket_path = absolute_path(vm["admin-socket"].as<string>());
+  if (vm.count("unix-socket"))
+    unix_socket_path = absolute_path(vm["unix-socket"].as<string>());
+  if (!unix_socket_path.empty() && unix_socket_path == admin_socket_path) {
+    cerr << "--unix-socket and --admin-socket must be different paths.\n";
+    exit(EXIT_FAILURE);
+  }
+  if (0 != http_port) {
+#ifdef HAVE_HTTP
+    if (http_port == port || http_port == metrics_port) {
+      cerr << "--http-port must differ from --port and --metrics-port.\n";
+      exit(EXIT_FAILURE);
+    }
+#else
+    cerr << "nsrlsvr was built without HTTP lookup support.\n";
+    exit(EXIT_FAILURE);
+#endif
+  }
+  boost::system::error_code address_error;
+  bind_address = boost::asio::ip::make_address(
+      vm["bind-address"].as<string>(), address_error);
+  if (address_error) {
+    cerr << vm["bind-address"].as<string>()
+         << " is not a valid IPv4 or IPv6 address.\n";
+    exit(EXIT_FAILURE);
+  }
+  dual_stack = vm["dual-stack"].as<bool>();
+  if (vm.count("allow"))
+    for (const auto& range : vm["allow"].as<vector<string>>())
+      if (!add_allowed_range(range)) {
+        cerr << range << " is not a valid address or CIDR range.\n";
+        exit(EXIT_FAILURE);
+      }
+  {
+    static const std::regex octal_re{"^[0-7]{1,4}$"};
+    const auto mask = vm["umask"].as<string>();
+    if (!std::regex_match(mask, octal_re) ||
+        0777 < std::stoul(mask, nullptr, 8)) {
+      cerr << "--umask must be octal, from 0 to 0777.\n";
+      exit(EXIT_FAILURE);
+    }
+    // Now, before --log-file is opened, as well as after daemonize().
+    file_mask = static_cast<mode_t>(std::stoul(mask, nullptr, 8));
+    umask(file_mask);
+    // The default mask would shut out every client that isn't running
+    // as nsrlsvr's own account, so only one asked for applies.
+    mask_unix_socket = !vm["umask"].defaulted();
+  }
+  if (vm.count("pid-file")) {
+    // daemonize() changes directory to /, so a relative path has to be
+    // pinned down now.  Checking the directory here also means an
+    // unwritable one is reported on the terminal, not just in syslog.
+    pid_file = vm["pid-file"].as<string>();
+    if (pid_file.empty()) {
+      cerr << "--pid-file needs a path.\n";
+      exit(EXIT_FAILURE);
+    }
+    pid_file = absolute_path(pid_file);
+    const auto directory = pid_file.substr(0, pid_file.rfind('/') + 1);
+    if (0 != access(directory.c_str(), W_OK)) {
+      cerr << "Can't write the PID file: " << directory
+           << " isn't a writable directory.\n";
+      exit(EXIT_FAILURE);
+    }
+  }
+  LogLevel threshold;
+  if (!parse_level(vm["log-level"].as<string>(), threshold)) {
+    cerr << vm["log-level"].as<string>() << " isn't a log level.\n";
+    exit(EXIT_FAILURE);
+  }
+  log_threshold = static_cast<int>(threshold);
+  const auto format = vm["log-format"].as<string>();
+  if (format != "text" && format != "json") {
+    cerr << "--log-format must be text or json.\n";
+    exit(EXIT_FAILURE);
+  }
+  log_json = format == "json";
+  if (vm.count("log-file")) {
+    const auto path = vm["log-file"].as<string>();
+    if (path.empty()) {
+      cerr << "--log-file needs a path.\n";
+      exit(EXIT_FAILURE);
+    }
+    log_path = absolute_path(path);
+    if (!open_log_file()) {
+      cerr << "Couldn't open " << log_path
+           << " for logging: " << std::strerror(errno) << "\n";
+      exit(EXIT_FAILURE);
+    }
+  }
+  if (vm.count("group") && !vm.count("user")) {
+    cerr << "--group needs --user as well.\n";
+    exit(EXIT_FAILURE);
+  }
+  if (vm.count("user")) {
+    const auto user = vm["user"].as<string>();
+    const passwd* account = getpwnam(user.c_str());
+    if (nullptr == account) {
+      cerr << "There's no user named " << user << ".\n";
+      exit(EXIT_FAILURE);
+    }
+    run_as_uid = account->pw_uid;
+    run_as_gid = account->pw_gid;
+    if (vm.count("group")) {
+      const auto group_name = vm["group"].as<string>();
+      const group* entry = getgrnam(group_name.c_str());
+      if (nullptr == entry) {
+        cerr << "There's no group named " << group_name << ".\n";
+        exit(EXIT_FAILURE);
+      }
+      run_as_gid = entry->gr_gid;
+    }
+    if (0 != geteuid()) {
+      cerr << "--user only works when nsrlsvr is started as root.\n";
+      exit(EXIT_FAILURE);
+    }
+    drop_privileges = true;
+  }
+  max_connections = vm["max-connections"].as<unsigned int>();
+  if (0 == max_connections) {
+    cerr << "--max-connections must be at least 1.\n";
+    exit(EXIT_FAILURE);
+  }
+  idle_timeout = vm["idle-timeout"].as<unsigned int>();
+  accept_queue = vm["accept-queue"].as<unsigned int>();
+  listen_backlog = vm["backlog"].as<unsigned int>();
+  if (0 == listen_backlog || INT_MAX < listen_backlog) {
+    cerr << "--backlog must be between 1 and " << INT_MAX << ".\n";
+    exit(EXIT_FAILURE);
+  }
+  {
+    static const std::map<string, LoadingBehavior> behaviors{
+        {"refuse", LoadingBehavior::Refuse},
+        {"reject", LoadingBehavior::Reject},
+        {"hold", LoadingBehavior::Hold}};
+    const auto found = behaviors.find(vm["loading-behavior"].as<string>());
+    if (behaviors.cend() == found) {
+      cerr << "--loading-behavior must be refuse, reject or hold.\n";
+      exit(EXIT_FAILURE);
+    }
+    loading_behavior = found->second;
+  }
+  tcp_keepalive = vm["tcp-keepalive"].as<unsigned int>();
+  max_hashes = vm["max-query-hashes"].as<unsigned int>();
+  max_line = vm["max-line-bytes"].as<size_t>();
+  session_workers = vm.count("workers") ? vm["workers"].as<unsigned int>()
+                                        : worker_count();
+  if (0 == session_workers) {
+    cerr << "--workers must be at least 1.\n";
+    exit(EXIT_FAILURE);
+  }
+  if (0 < vm["cache-size"].as<size_t>())
+    cache.reset(new QueryCache(vm["cache-size"].as<size_t>()));
+  const auto rate = vm["rate-limit"].as<double>();
+  if (0 > rate) {
+    cerr << "--rate-limit can't be negative.\n";
+    exit(EXIT_FAILURE);
+  }
+  if (0 < rate) {
+    const auto burst = vm.count("rate-burst")
+                           ? vm["rate-burst"].as<double>()
+                           : std::max(rate, 1.0);
+    if (1.0 > burst) {
+      cerr << "--rate-burst must be at least 1.\n";
+      exit(EXIT_FAILURE);
+    }
+    limiter.reset(new RateLimiter(rate, burst));
+  }
+  if (vm.count("tls-cert") != vm.count("tls-key")) {
+    cerr << "--tls-cert and --tls-key must be given together.\n";
+    exit(EXIT_FAILURE);
+  }
+  if (vm.count("tls-cert")) {
+#ifdef HAVE_OPENSSL
+    try {
+      tls_cert_path = resolve_path(vm["tls-cert"].as<string>(), EXIT_FAILURE);
+      tls_key_path = resolve_path(vm["tls-key"].as<string>(), EXIT_FAILURE);
+      tls_context = load_tls_context(tls_cert_path, tls_key_path);
+    } catch (std::runtime_error& e) {
+      cerr << e.what() << "\n";
+      exit(EXIT_FAILURE);
+    }
+#else
+    cerr << "nsrlsvr was built without TLS support.\n";
+    exit(EXIT_FAILURE);
+#endif
+  }
+  progress_interval = vm["progress-interval"].as<uint64_t>();
+  load_timeout = vm["load-timeout"].as<unsigned int>();
+  watch = vm["watch"].as<bool>();
+  watch_settle = vm["watch-settle"].as<unsigned int>();
+  rds_sha1 = vm["rds-sha1"].as<bool>();
+  invert = vm["invert"].as<bool>();
+  allow_empty = vm["allow-empty"].as<bool>();
+  if (vm.count("expect-hashes"))
+    expected_hashes = vm["expect-hashes"].as<uint64_t>();
+  expect_tolerance = vm["expect-tolerance"].as<double>();
+  if (0 > expect_tolerance || 100 < expect_tolerance) {
+    cerr << "--expect-tolerance must be between 0 and 100.\n";
+    exit(EXIT_FAILURE);
+  }
+  strict = vm["strict"].as<bool>();
+  const auto hit = vm["hit-char"].as<string>();
+  const auto miss = vm["miss-char"].as<string>();
+  if (1 != hit.size() || 1 != miss.size() ||
+      !isgraph(static_cast<unsigned char>(hit[0])) ||
+      !isgraph(static_cast<unsigned char>(miss[0]))) {
+    cerr << "--hit-char and --miss-char must each be one printable, "
+            "non-space character.\n";
+    exit(EXIT_FAILURE);
+  }
+  if (hit == miss) {
+    cerr << "--hit-char and --miss-char must be different.\n";
+    exit(EXIT_FAILURE);
+  }
+  hit_mark = hit[0];
+  miss_mark = miss[0];
+  use_bloom = vm["bloom"].as<bool>();
+  bloom_verify = vm["bloom-verify"].as<bool>();
+  bloom_fpr = vm["bloom-fpr"].as<double>();
+  if (bloom_fpr <= 0.0 || bloom_fpr >= 1.0) {
+    cerr << "The Bloom filter false-positive rate must be between 0 and 1.\n";
+    exit(EXIT_FAILURE);
+  }
+  if (vm.count("build-index")) index_output = vm["build-index"].as<string>();
+  bench = vm["bench"].as<bool>();
+  bench_count = vm["bench-count"].as<uint64_t>();
+  if (bench && (dry_run || !index_output.empty())) {
+    cerr << "--bench can't be combined with --dry-run or --build-index.\n";
+    exit(EXIT_FAILURE);
+  }
+  if (bench && 0 == bench_count) {
+    cerr << "--bench-count must be at least 1.\n";
+    exit(EXIT_FAILURE);
+  }
+  verify = vm["verify"].as<bool>();
+  if (verify && (dry_run || bench || !index_output.empty())) {
+    cerr << "--verify can't be combined with --dry-run, --bench or "
+            "--build-index.\n";
+    exit(EXIT_FAILURE);
+  }
+  if (vm.count("sample")) {
+    const auto sample = vm["sample"].as<string>();
+    if (!verify) {
+      cerr << "--sample only goes with --verify.\n";
+      exit(EXIT_FAILURE);
+    }
+    if (!read_sample(sample, sample_hashes)) {
+      cerr << "Couldn't read " << sample << ".\n";
+      exit(EXIT_FAILURE);
+    }
+    if (sample_hashes.empty()) {
+      cerr << sample << " holds no hashes for --verify to look up.\n";
+      exit(EXIT_FAILURE);
+    }
+  }
+  log_to_stderr = dry_run || bench || verify || !index_output.empty();
+  if (use_bloom && !index_output.empty()) {
+    cerr << "--bloom can't be combined with --build-index.\n";
+    exit(EXIT_FAILURE);
+  }
+  use_compact = vm["compact"].as<bool>();
+  if (use_compact && (use_bloom || !index_output.empty())) {
+    cerr << "--compact can't be combined with --bloom or --build-index.\n";
+    exit(EXIT_FAILURE);
+  }
+
+  shard_bits = vm["shard-bits"].as<unsigned int>();
+  if (shard_bits > 24) {
+    cerr << "--shard-bits must be between 0 and 24.\n";
     exit(EXIT_FAILURE);
   }
-  hashes_location = string(filepath);
-  if (not ifstream(hashes_location.c_str())) {
-    cerr << "Could not open " + hashes_location + " for reading.\n";
+  if (0 != shard_bits && (use_bloom || use_compact)) {
+    cerr << "--shard-bits can't be combined with --bloom or --compact.\n";
     exit(EXIT_FAILURE);
   }
+
+  allow_nearest = vm["nearest"].as<bool>();
+  if (allow_nearest && (use_bloom || use_compact)) {
+    cerr << "--nearest can't be combined with --bloom or --compact.\n";
+    exit(EXIT_FAILURE);
+  }
+  track_sources = vm["track-sources"].as<bool>();
+  if (track_sources && (use_bloom || use_compact || vm.count("index") ||
+                        !index_output.empty())) {
+    cerr << "--track-sources can't be combined with --bloom, --compact, "
+            "--index or --build-index.\n";
+    exit(EXIT_FAILURE);
+  }
+
+  if (bloom_verify && !(use_bloom && vm.count("index"))) {
+    cerr << "--bloom-verify needs --bloom and --index.\n";
+    exit(EXIT_FAILURE);
+  }
+  if (vm.count("index")) {
+    if ((use_bloom && !bloom_verify) || use_compact ||
+        !index_output.empty()) {
+      cerr << "--index can't be combined with --compact or --build-index, "
+              "or with --bloom unless --bloom-verify is given.\n";
+      exit(EXIT_FAILURE);
+    }
+    index_location = resolve_path(vm["index"].as<string>());
+  }
+  {
+    static const std::map<string, int> advice{
+        {"random", MADV_RANDOM},
+        {"sequential", MADV_SEQUENTIAL},
+        {"willneed", MADV_WILLNEED},
+        {"normal", MADV_NORMAL}};
+    const auto found = advice.find(vm["madvise"].as<string>());
+    if (advice.cend() == found) {
+      cerr << "--madvise must be random, sequential, willneed or normal.\n";
+      exit(EXIT_FAILURE);
+    }
+    if (!vm["madvise"].defaulted() && index_location.empty()) {
+      cerr << "--madvise only goes with --index.\n";
+      exit(EXIT_FAILURE);
+    }
+    index_advice = found->second;
+  }
+  if (vm.count("sha1-md5-map"))
+    sha1_md5_map_path = resolve_path(vm["sha1-md5-map"].as<string>());
+  // With --index the hash files are only a fallback, so there's no
+  // insisting they be readable yet.
+  auto files = vm["file"].as<vector<string>>();
+  // Only the default is searched for; a --file that's missing is an
+  // error like any other.
+  if (vm["file"].defaulted()) files = {default_hash_file()};
+  hash_files.clear();
+  for (const auto& path : files)
+    hash_files.push_back(index_location.empty() ? resolve_path(path)
+                                                : absolute_path(path));
+  if (vm["file"].defaulted())
+    default_file_note = "no --file given; reading hashes from " +
+                        hash_files.front();
+  if (track_sources && hash_files.size() > 256) {
+    cerr << "--track-sources can keep track of at most 256 files.\n";
+    exit(EXIT_FAILURE);
+  }
+
+  if (vm.count("dataset")) {
+    static const std::regex name_re{"^[A-Za-z0-9_.-]+$"};
+    for (const auto& spec : vm["dataset"].as<vector<string>>()) {
+      const auto equals = spec.find('=');
+      const auto name = spec.substr(0, equals);
+      if (string::npos == equals || !std::regex_match(name, name_re) ||
+          "default" == name || equals + 1 == spec.size()) {
+        cerr << "--dataset wants NAME=FILE, where NAME is letters, digits, "
+                "'.', '_' and '-' and isn't \"default\".\n";
+        exit(EXIT_FAILURE);
+      }
+      dataset_files[name].push_back(resolve_path(spec.substr(equals + 1)));
+    }
+    for (const auto& named : dataset_files)
+      if (track_sources && named.second.size() > 256) {
+        cerr << "--track-sources can keep track of at most 256 files per "
+                "dataset.\n";
+        exit(EXIT_FAILURE);
+      }
+    if (!index_output.empty()) {
+      cerr << "--dataset can't be combined with --build-index.\n";
+      exit(EXIT_FAILURE);
+    }
+  }
 }
 }  // namespace
 
-/** The set of all loaded hashes, represented as a const reference. */
-const vector<pair64>& hashes{hash_set};
+/** The data queries should be answered from right now.  Holding on to
+ * it keeps it alive across a reload, so a lookup sees one consistent
+ * set of hashes from start to finish.
+ */
+std::shared_ptr<const HashData> current_hashes() {
+  std::lock_guard<std::mutex> lock(dataset_mutex);
+  return dataset;
+}
+
+/** As current_hashes(), but for the --dataset called name, or the main
+ * hash files if name is empty.
+ * @return nullptr if there's no such dataset
+ */
+std::shared_ptr<const HashData> current_hashes(const string& name) {
+  if (name.empty()) return current_hashes();
+  std::lock_guard<std::mutex> lock(dataset_mutex);
+  const auto found = named_datasets.find(name);
+  return named_datasets.cend() == found ? nullptr : found->second;
+}
+
+/** The names of the --dataset datasets, in order. */
+vector<string> dataset_names() {
+  vector<string> rv;
+  for (const auto& named : dataset_files) rv.push_back(named.first);
+  return rv;
+}
+
+/** When nsrlsvr started. */
+const steady_clock::time_point& start_time{start};
+
+/** Set once the process has been asked to shut down. */
+const volatile sig_atomic_t& stop_requested{stopping};
+
+/** Per-address rate limiting, if --rate-limit was given. */
+const std::unique_ptr<RateLimiter>& rate_limiter{limiter};
+/** The --cache-size cache of lookup results, if there is one. */
+const std::unique_ptr<QueryCache>& query_cache{cache};
+
+/** Most hashes a single QUERY may carry, or 0 for no limit. */
+const unsigned int& max_query_hashes{max_hashes};
+
+/** Longest line a client may send, or 0 for no limit. */
+const size_t& max_line_bytes{max_line};
+
+/** Whether QUERY's bitstring marks misses rather than hits. */
+const bool& invert_results{invert};
+
+/** Whether --nearest allows NEAREST. */
+const bool& nearest_enabled{allow_nearest};
+
+/** What QUERY's bitstring uses for 1 and 0. */
+const char& hit_char{hit_mark};
+const char& miss_char{miss_mark};
+
+/** Whether --allow lets a client at peer connect. */
+bool is_allowed_client(const boost::asio::ip::address& peer) {
+  using namespace boost::asio::ip;
+  if (allowed_v4.empty() && allowed_v6.empty()) return true;
+  // A dual-stack listener sees IPv4 clients as ::ffff:a.b.c.d.
+  if (peer.is_v6() && peer.to_v6().is_v4_mapped())
+    return is_allowed_client(peer.to_v6().to_v4());
+  if (peer.is_v4())
+    return std::any_of(allowed_v4.cbegin(), allowed_v4.cend(),
+                       [&peer](const network_v4& range) {
+                         return range == make_network_v4(peer.to_v4(),
+                                                         range.prefix_length())
+                                             .canonical();
+                       });
+  return std::any_of(allowed_v6.cbegin(), allowed_v6.cend(),
+                     [&peer](const network_v6& range) {
+                       return range == make_network_v6(peer.to_v6(),
+                                                       range.prefix_length())
+                                           .canonical();
+                     });
+}
+
+/** Clients connected, whether being served or waiting for a worker. */
+const std::atomic<unsigned int>& active_sessions{sessions};
+
+/** Whether the hashes have loaded, and whether the last reload worked. */
+const std::atomic<ServerState>& server_state{state};
+
+/** Whether a message at level would be logged, for callers that would
+ * rather not build one that's going to be dropped.
+ */
+bool log_enabled(const LogLevel level) {
+  return static_cast<int>(level) <= log_threshold;
+}
 
 /** Writes to syslog with the given priority level.
 
@@ -278,11 +1969,102 @@ const vector<pair64>& hashes{hash_set};
     @param msg The message to write
 */
 void log(const LogLevel level, const string&& msg) {
-  if (dry_run)
+  if (!log_enabled(level)) return;
+  if (log_json && (log_to_stderr || nullptr != log_file)) {
+    std::array<char, 32> stamp;
+    const auto now = time(nullptr);
+    struct tm utc;
+    gmtime_r(&now, &utc);
+    strftime(stamp.data(), stamp.size(), "%Y-%m-%dT%H:%M:%SZ", &utc);
+    const auto line = string("{\"timestamp\":\"") + stamp.data() +
+                      "\",\"level\":\"" + level_name(level) +
+                      "\",\"target\":\"nsrlsvr\",\"pid\":" +
+                      to_string(getpid()) +
+                      ",\"message\":" + json_string(msg) + "}\n";
+    std::lock_guard<std::mutex> lock(log_mutex);
+    if (log_to_stderr)
+      cerr << line;
+    else
+      fputs(line.c_str(), log_file);
+  } else if (log_to_stderr) {
     cerr << msg << "\n";
-  else
+  } else if (nullptr != log_file) {
+    std::array<char, 32> stamp;
+    const auto now = time(nullptr);
+    struct tm local;
+    localtime_r(&now, &local);
+    strftime(stamp.data(), stamp.size(), "%Y-%m-%d %H:%M:%S", &local);
+    std::lock_guard<std::mutex> lock(log_mutex);
+    fprintf(log_file, "%s nsrlsvr[%d]: %s: %s\n", stamp.data(),
+            static_cast<int>(getpid()), level_name(level), msg.c_str());
+  } else {
     syslog(LOG_MAKEPRI(LOG_USER, static_cast<int>(level)), "%s", msg.c_str());
+  }
+}
+
+namespace {
+/** Loads the hashes the server starts out with.
+ * @param known_hashes filled with MD5s for --bench or --verify to look
+ * up, unless --sample gave them
+ * @return false, having said why, if they couldn't be loaded
+ */
+bool load_at_startup(vector<string>& known_hashes) {
+  const bool sample = bench || (verify && sample_hashes.empty());
+  try {
+    publish(load_main_data(sample ? &known_hashes : nullptr));
+    publish_named(load_named_datasets());
+  } catch (LoadError& e) {
+    report_load_error(e);
+    return false;
+  } catch (std::bad_alloc&) {
+    // Whichever allocation it was, sorting included, it's reported as
+    // running out of memory.
+    report_load_error(LoadError(LoadError::Kind::OutOfMemory,
+                                "couldn't allocate enough memory"));
+    return false;
+  } catch (std::exception& e) {
+    log(LogLevel::ALERT, string("loading failed: ") + e.what());
+    return false;
+  }
+  return true;
+}
+
+/** Runs load_at_startup() on a worker thread and waits up to
+ * --load-timeout seconds for it.  A read stuck on a hung NFS mount
+ * can't be interrupted, so if time runs out the process quick_exit()s
+ * with the worker still blocked, skipping the destructors that might
+ * trip over it.
+ */
+bool load_with_timeout(vector<string>& known_hashes) {
+  std::packaged_task<bool()> task{
+      [&known_hashes]() { return load_at_startup(known_hashes); }};
+  auto loaded = task.get_future();
+
+  // Signals are for the main thread's loop.
+  const auto signals = loop_signals();
+  sigset_t saved_mask;
+  pthread_sigmask(SIG_BLOCK, &signals, &saved_mask);
+  try {
+    std::thread(std::move(task)).detach();
+  } catch (std::system_error& e) {
+    log(LogLevel::ALERT, string("couldn't start loading: ") + e.what());
+    return false;
+  }
+  pthread_sigmask(SIG_SETMASK, &saved_mask, nullptr);
+
+  if (std::future_status::ready !=
+      loaded.wait_for(std::chrono::seconds(load_timeout))) {
+    log(LogLevel::ALERT, "loading took more than the " +
+                             to_string(load_timeout) +
+                             " seconds --load-timeout allows; is the hash "
+                             "file on a slow or hung mount?");
+    log(LogLevel::ALERT, "shutting down!");
+    fflush(nullptr);
+    std::quick_exit(exit_load_failed);
+  }
+  return loaded.get();
 }
+}  // namespace
 
 /** Entry point for the application.
 
@@ -293,50 +2075,292 @@ int main(int argc, char* argv[]) {
   static_assert(sizeof(unsigned long long) == 8,
                 "wait, what kind of system is this?");
   parse_options(argc, argv);
+  // A client that hangs up mid-response would otherwise take the whole
+  // server down with it; the failed write is handled where it happens.
+  // This comes first so it covers the metrics port and any clients
+  // turned away while loading, too.
+  signal(SIGPIPE, SIG_IGN);
 
-  if (!dry_run) daemonize();
+  // Under systemd the service manager is already our parent; forking
+  // would leave it watching the wrong process for READY=1.
+  if (!log_to_stderr && nullptr == getenv("NOTIFY_SOCKET")) daemonize();
+  if (!default_file_note.empty() && index_location.empty())
+    log(LogLevel::INFO, std::move(default_file_note));
+  const bool serving =
+      !dry_run && !bench && !verify && index_output.empty();
+  if (!pid_file.empty() && serving) write_pid_file();
 
-  load_hashes();
+  // The metrics port, and with it the health check, comes up before the
+  // hashes are loaded, so a long load can be told apart from a hang.
+  boost::asio::io_service io_service;
+  tcp::acceptor metrics_acceptor(io_service);
+  if (serving && 0 != metrics_port) {
+    if (!start_listening(metrics_acceptor, metrics_port))
+      return exit_listen_failed;
+    const auto signals = loop_signals();
+    sigset_t saved_mask;
+    pthread_sigmask(SIG_BLOCK, &signals, &saved_mask);
+    try {
+      std::thread(serve_metrics, std::ref(io_service),
+                  std::ref(metrics_acceptor))
+          .detach();
+    } catch (std::system_error& e) {
+      log(LogLevel::ALERT, string("couldn't serve metrics: ") + e.what());
+      return exit_start_failed;
+    }
+    pthread_sigmask(SIG_SETMASK, &saved_mask, nullptr);
+  }
 
-  // The following line helps avoid zombie processes.  Normally parents
-  // need to reap their children in order to prevent zombie processes;
-  // if SIGCHLD is set to SIG_IGN, though, the processes can terminate
-  // normally.
-  signal(SIGCHLD, SIG_IGN);
+  // With --loading-behavior reject or hold the query port comes up now
+  // too, so clients that show up mid-load aren't simply refused.
+  tcp::acceptor acceptor(io_service);
+  std::atomic<bool> loading{true};
+  std::thread rejecter;
+  if (serving && LoadingBehavior::Refuse != loading_behavior) {
+    if (!start_listening(acceptor, port)) return exit_listen_failed;
+    if (LoadingBehavior::Reject == loading_behavior) {
+      const auto signals = loop_signals();
+      sigset_t saved_mask;
+      pthread_sigmask(SIG_BLOCK, &signals, &saved_mask);
+      try {
+        rejecter = std::thread(reject_while_loading, std::ref(io_service),
+                               std::ref(acceptor), std::cref(loading));
+      } catch (std::system_error& e) {
+        log(LogLevel::ALERT, string("couldn't start: ") + e.what());
+        return exit_start_failed;
+      }
+      pthread_sigmask(SIG_SETMASK, &saved_mask, nullptr);
+    }
+  }
 
-  if (dry_run) return EXIT_SUCCESS;
+  const auto load_started = steady_clock::now();
+  // Stamped before loading, so a change made while the load runs still
+  // gets noticed.
+  std::unique_ptr<HashFileWatch> watcher;
+  if (watch && serving) watcher.reset(new HashFileWatch());
+  vector<string> known_hashes{sample_hashes};
+  const bool loaded = 0 == load_timeout
+                          ? load_at_startup(known_hashes)
+                          : load_with_timeout(known_hashes);
+  loading = false;
+  if (rejecter.joinable()) rejecter.join();
+  if (!loaded) {
+    log(LogLevel::ALERT, "shutting down!");
+    return exit_load_failed;
+  }
+  // An empty or misformatted file would otherwise give a server that
+  // looks healthy but answers every query with a miss.
+  if (0 == loaded_hash_count() && !allow_empty) {
+    log(LogLevel::ALERT, "no hashes were loaded; check that the hash files "
+                         "are the right ones, or pass --allow-empty");
+    log(LogLevel::ALERT, "shutting down!");
+    return exit_load_failed;
+  }
+  if (!loaded_expected_count(loaded_hash_count()) && strict) {
+    log(LogLevel::ALERT, "shutting down!");
+    return exit_load_failed;
+  }
+  state = ServerState::Ready;
+  log_memory_usage();
+  if (!index_output.empty())
+    return write_index() ? EXIT_SUCCESS : exit_start_failed;
+  if (bench) {
+    const std::chrono::duration<double> load_time =
+        steady_clock::now() - load_started;
+    run_benchmark(known_hashes, bench_count, load_time.count());
+    return EXIT_SUCCESS;
+  }
+  // A Bloom filter's false positives would fail the misses by chance.
+  if (verify)
+    return run_verify(known_hashes, !use_bloom) ? EXIT_SUCCESS
+                                                : exit_verify_failed;
 
-  boost::asio::io_service io_service;
-  const char* listen_address = "::";
-  boost::asio::ip::address address = boost::asio::ip::address::from_string(listen_address);
-  tcp::endpoint endpoint(address, port);
-  tcp::acceptor acceptor(io_service, endpoint.protocol());
-  if (endpoint.protocol() == tcp::v6()) {
-    boost::system::error_code ec;
-    acceptor.set_option(boost::asio::ip::v6_only(false), ec);
+  if (dry_run) {
+    report_dry_run();
+    return EXIT_SUCCESS;
+  }
+
+  tcp::acceptor http_acceptor(io_service);
+  if ((!acceptor.is_open() && !start_listening(acceptor, port)) ||
+      (0 != http_port && !start_listening(http_acceptor, http_port))) {
+    log(LogLevel::ALERT, "shutting down!");
+    return exit_listen_failed;
   }
-  acceptor.bind(endpoint);
-  acceptor.listen();
+  std::unique_ptr<boost::asio::local::stream_protocol::acceptor> admin;
+  std::unique_ptr<boost::asio::local::stream_protocol::acceptor> local;
+  try {
+    if (!admin_socket_path.empty())
+      admin = open_unix_socket(io_service, admin_socket_path, 0600,
+                               listen_backlog);
+    if (!unix_socket_path.empty())
+      local = open_unix_socket(
+          io_service, unix_socket_path,
+          mask_unix_socket ? 0666 & ~file_mask : 0666, listen_backlog);
+  } catch (std::runtime_error& e) {
+    log(LogLevel::ALERT, string("couldn't listen: ") + e.what());
+    if (admin) unlink(admin_socket_path.c_str());
+    return exit_listen_failed;
+  }
+  if (drop_privileges) become_unprivileged();
+
+  // accept() mustn't be restarted after a signal, or a shutdown request
+  // would go unnoticed until the next client came along.
+  listen_fd = acceptor.native_handle();
+  install_stop_handler(false);
+  install_handler(SIGHUP, request_reload, false);
+  install_handler(SIGUSR1, request_stats, false);
+
+  const auto signals = loop_signals();
+  sigset_t saved_mask;
+  pthread_sigmask(SIG_BLOCK, &signals, &saved_mask);
+  vector<std::thread> workers;
+  try {
+    for (unsigned int idx = 0; idx < session_workers; ++idx)
+      workers.emplace_back(run_worker);
+#ifdef HAVE_HTTP
+    if (0 != http_port)
+      std::thread(serve_lookups, std::ref(io_service), std::ref(http_acceptor))
+          .detach();
+#endif
+    if (admin)
+      std::thread(serve_admin, std::ref(io_service), std::ref(*admin))
+          .detach();
+  } catch (std::system_error& e) {
+    log(LogLevel::ALERT, string("couldn't start workers: ") + e.what());
+    close_queue();
+    for (auto& worker : workers) worker.join();
+    return exit_start_failed;
+  }
+  pthread_sigmask(SIG_SETMASK, &saved_mask, nullptr);
+
+  log(LogLevel::INFO, startup_banner());
+  log_backlog();
+  // Loaded and listening: tell systemd we're up, and if it wants
+  // watchdog pings, wake up often enough to send them on time.
+  notify_systemd("READY=1");
+  const auto watchdog = systemd_watchdog_interval();
+  auto next_ping = steady_clock::now();
+  int poll_ms{1000};
+  if (watchdog.count())
+    poll_ms = static_cast<int>(std::max<long long>(
+        1, std::min<long long>(poll_ms, watchdog.count() / 4000)));
+  // A reload runs on a thread of its own, so that clients go on being
+  // accepted, and answered from the previous hashes, while it loads.
+  std::thread reloader;
+  std::atomic<bool> reload_running{false};
+  // poll() keeps saying a listener is ready while accept() keeps
+  // failing on it, so these are all that stop the loop spinning.
+  AcceptBackoff backoff{"port " + to_string(port)};
+  AcceptBackoff local_backoff{"the Unix socket"};
+
+  while (!stopping) {
+    // Wake up every so often even with no clients around, so a SIGHUP
+    // that lands just before we'd block in accept() isn't left waiting.
+    if (stats_requested) {
+      stats_requested = 0;
+      log_statistics();
+    }
+    if (watcher && watcher->settled_change()) {
+      log(LogLevel::INFO, "hash files changed");
+      reloading = 1;
+    }
+    if (reloader.joinable() && !reload_running) {
+      reloader.join();
+      notify_systemd("READY=1");
+    }
+    // A SIGHUP during a reload is kept for once it's done.
+    if (reloading && !reload_running) {
+      reloading = 0;
+      // Reopening lets logrotate move the old file out of the way.
+      if (!log_path.empty() && !open_log_file())
+        log(LogLevel::WARN, "couldn't reopen " + log_path +
+                                "; still logging to the old file");
+      notify_systemd("RELOADING=1");
+      if (watcher) watcher->reloading();
+      reload_running = true;
+      const auto signals = loop_signals();
+      sigset_t saved_mask;
+      pthread_sigmask(SIG_BLOCK, &signals, &saved_mask);
+      try {
+        reloader = std::thread([&reload_running]() {
+          reload_hashes();
+          reload_running = false;
+        });
+      } catch (std::system_error& e) {
+        log(LogLevel::ALERT, string("couldn't start the reload: ") + e.what());
+        reload_running = false;
+        notify_systemd("READY=1");
+      }
+      pthread_sigmask(SIG_SETMASK, &saved_mask, nullptr);
+    }
+    // The pings come from here so that a wedged accept loop gets
+    // noticed rather than covered for.  A reload doesn't hold the loop
+    // up, so they go on through one.
+    if (watchdog.count() && steady_clock::now() >= next_ping) {
+      notify_systemd("WATCHDOG=1");
+      next_ping = steady_clock::now() + watchdog / 2;
+    }
+    std::array<pollfd, 2> waiting{{{listen_fd, POLLIN, 0},
+                                   {local ? local->native_handle() : -1,
+                                    POLLIN, 0}}};
+    if (0 >= poll(waiting.data(), local ? 2 : 1, poll_ms)) continue;
 
-  while (true) {
-    tcp::iostream stream;
     boost::system::error_code error;
-    acceptor.accept(*stream.rdbuf(), error);
+    if (local && (waiting[1].revents & POLLIN)) {
+      boost::asio::local::stream_protocol::socket socket(io_service);
+      local->accept(socket, error);
+      if (error) {
+        local_backoff.failed(error);
+      } else {
+        local_backoff.succeeded();
+        admit_client(std::move(socket), "local client", true);
+      }
+    }
+    if (!(waiting[0].revents & POLLIN)) continue;
+
+    tcp::socket socket(io_service);
+    acceptor.accept(socket, error);
 
     if (error) {
+      if (!stopping) backoff.failed(error);
       continue;
     }
-    string ipaddr = stream.socket().remote_endpoint().address().to_string();
-    log(LogLevel::ALERT, string("accepted a client: ") + ipaddr);
-
-    if (0 == fork()) {
-      log(LogLevel::ALERT, "calling handle_client");
-      handle_client(stream);
-      return 0;
+    backoff.succeeded();
+    // A client can hang up before we get around to asking who it was.
+    const auto peer = socket.remote_endpoint(error);
+    if (error) continue;
+    string ipaddr = peer.address().to_string();
+    if (!is_allowed_client(peer.address())) {
+      log(LogLevel::DEBUG, "refused " + ipaddr + ": not covered by --allow");
+      continue;
     }
+    tune_socket(socket);
+    admit_client(std::move(socket), ipaddr, false);
   }
 
-  // Note that as is normal for daemons, the exit point is never
-  // reached.  This application does not normally terminate.
+  log(LogLevel::INFO, "shutting down");
+  notify_systemd("STOPPING=1");
+  acceptor.close();
+  if (admin) unlink(admin_socket_path.c_str());
+  if (local) unlink(unix_socket_path.c_str());
+  close_queue();
+  // A reload that's still loading is of no use now, and could take
+  // minutes to finish, so it's left behind like a stuck session.
+  const bool reload_left = reload_running;
+  if (reload_left) {
+    log(LogLevel::INFO, "abandoning the reload in progress");
+    reloader.detach();
+  } else if (reloader.joinable()) {
+    reloader.join();
+  }
+  // Returning would run static destructors out from under a session,
+  // or a reload, that's still going.
+  if (!drain_sessions() || reload_left) {
+    log(LogLevel::ALERT, "exiting without waiting for what's still "
+                         "running");
+    fflush(nullptr);
+    std::quick_exit(EXIT_SUCCESS);
+  }
+  for (auto& worker : workers) worker.join();
   return EXIT_SUCCESS;
 }
@@ -17,31 +17,290 @@ OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
 #ifndef MAIN_H
 #define MAIN_H
 
+#include <sys/types.h>
 #include <syslog.h>
+#include <algorithm>
+#include <array>
+#include <atomic>
 #include <boost/asio.hpp>
+#include <boost/program_options.hpp>
+#include <chrono>
 #include <cstdint>
+#include <iostream>
+#include <list>
+#include <memory>
+#include <mutex>
+#include <set>
+#include <stdexcept>
 #include <string>
+#include <unordered_map>
 #include <utility>
+#include <vector>
+#ifdef HAVE_OPENSSL
+#include <boost/asio/ssl.hpp>
+#endif
+#include "engine.h"
+
+std::shared_ptr<const HashData> current_hashes();
+std::shared_ptr<const HashData> current_hashes(const std::string& name);
+std::vector<std::string> dataset_names();
+
+/** Token-bucket rate limiting keyed by client address, shared by all of
+ * that client's connections.  Each bucket holds up to `burst` tokens
+ * and refills at `rate` tokens per second.
+ */
+class RateLimiter {
+ public:
+  RateLimiter(const double rate, const double burst);
+  bool allow(const std::string& peer);
 
-// Note: C++11 guarantees an unsigned long long will be at least 64 bits.
-// A compile-time assert in main.cc guarantees it will ONLY be 64 bits.
-using pair64 = std::pair<unsigned long long, unsigned long long>;
+ private:
+  struct Bucket {
+    double tokens;
+    std::chrono::steady_clock::time_point updated;
+  };
 
-enum class LogLevel {
-  INFO = LOG_INFO,
-  WARN = LOG_WARNING,
-  DEBUG = LOG_DEBUG,
-  CRITICAL = LOG_CRIT,
-  ALERT = LOG_ALERT,
-  EMERGENCY = LOG_EMERG
+  void evict_idle(const std::chrono::steady_clock::time_point now);
+
+  double rate;
+  double burst;
+  std::mutex mutex;
+  std::unordered_map<std::string, Bucket> buckets;
+  std::chrono::steady_clock::time_point last_sweep;
 };
 
+/** A least-recently-used cache of lookup results for --cache-size,
+ * shared by every session.  It holds results for one HashData at a
+ * time, and a lookup against any other, as after a reload, empties it.
+ */
+class QueryCache {
+ public:
+  explicit QueryCache(const size_t capacity);
+  bool find(const std::shared_ptr<const HashData>& data,
+            const std::string& hash, bool& found);
+  void insert(const std::shared_ptr<const HashData>& data,
+              const std::string& hash, const bool found);
+
+ private:
+  void switch_to(const std::shared_ptr<const HashData>& data);
+
+  size_t capacity;
+  std::mutex mutex;
+  std::weak_ptr<const HashData> owner;
+  // Most recently used first.
+  std::list<std::pair<std::string, bool>> entries;
+  std::unordered_map<std::string, decltype(entries)::iterator> index;
+};
+
+/** A std::streambuf over a connected socket, for handle_client() to
+ * run a session on.  Subclasses supply the transport.  A read that
+ * gives up because the socket's receive timeout passed ends input
+ * just like a disconnect, but timed_out() can tell the two apart, and
+ * client_left() tells when a read or write failed because the client
+ * reset or closed the connection.
+ */
+class SocketBuffer : public std::streambuf {
+ public:
+  SocketBuffer();
+  bool timed_out() const { return idle; }
+  bool client_left() const { return gone; }
+
+ protected:
+  /** Reads up to len bytes, returning 0 at end of input and -1 on an
+   * error; on a timeout, also sets idle. */
+  virtual long receive(char* buffer, const std::size_t len) = 0;
+  /** Writes all len bytes, returning false on an error. */
+  virtual bool transmit(const char* buffer, const std::size_t len) = 0;
+
+  int_type underflow() override;
+  int_type overflow(int_type ch) override;
+  int sync() override;
+  /** Sets gone if errno says a failed read or write was the client
+   * going away. */
+  void note_error();
+
+  bool idle{false};
+  bool gone{false};
+
+ private:
+  std::array<char, 4096> input;
+  std::array<char, 4096> output;
+};
+
+/** A SocketBuffer over a plain TCP connection. */
+class PlainSocketBuffer : public SocketBuffer {
+ public:
+  explicit PlainSocketBuffer(const int fd) : fd{fd} {}
+
+ protected:
+  long receive(char* buffer, const std::size_t len) override;
+  bool transmit(const char* buffer, const std::size_t len) override;
+
+ private:
+  const int fd;
+};
+
+/** Paces an accept loop after accept() fails.  An error that persists,
+ * such as running out of descriptors, would otherwise have the loop
+ * retry at once, pinning a core and flooding the log.  Each failure in
+ * a row waits twice as long as the one before, from 10ms up to a
+ * second, and only the first of a run is logged.
+ */
+class AcceptBackoff {
+ public:
+  /** @param what names the listener in the log, e.g. "the admin socket" */
+  explicit AcceptBackoff(const std::string& what) : what{what} {}
+
+  /** Logs error if it's the first of a run, then waits. */
+  void failed(const boost::system::error_code& error);
+  void succeeded() { delay_ms = 0; }
+
+ private:
+  std::string what;
+  unsigned int delay_ms{0};
+};
+
+/** The parts of an HTTP request the built-in endpoints look at. */
+struct HttpRequest {
+  std::string method;
+  std::string path;
+  std::string query;
+  std::string body;
+};
+
+struct HttpResponse {
+  HttpResponse(const int status, const std::string& content_type,
+               const std::string& body);
+
+  int status;
+  std::string content_type;
+  std::string body;
+};
+
+/** Hashes added or removed at runtime through --admin-socket, as
+ * uppercase hex.  They apply on top of whatever was loaded, and a
+ * reload leaves them in place.
+ */
+struct HashOverlay {
+  bool empty() const;
+  bool apply(const std::string& hash, const bool found) const;
+
+  std::set<std::string> added;
+  std::set<std::string> removed;
+};
+
+std::shared_ptr<const HashOverlay> current_overlay();
+
+/** A Prometheus histogram of durations, which any thread may add to
+ * without taking a lock.
+ */
+class Histogram {
+ public:
+  /** @param bounds each bucket's upper bound in seconds, ascending */
+  explicit Histogram(std::vector<double> bounds);
+  void observe(const std::chrono::duration<double> elapsed);
+  const std::vector<double>& bounds() const { return upper; }
+  /** How many observations were at most bounds()[idx], or were
+   * anything at all for idx == bounds().size().
+   */
+  uint64_t cumulative(const size_t idx) const;
+  uint64_t count() const { return observations; }
+  double sum() const { return sum_ns * 1e-9; }
+
+ private:
+  const std::vector<double> upper;
+  // One more than there are bounds, for +Inf.
+  std::unique_ptr<std::atomic<uint64_t>[]> counts;
+  std::atomic<uint64_t> observations{0};
+  std::atomic<uint64_t> sum_ns{0};
+};
+
+/** Running totals for --metrics-port, bumped as sessions go. */
+struct ServerMetrics {
+  std::atomic<uint64_t> connections{0};
+  std::atomic<uint64_t> queries{0};
+  std::atomic<uint64_t> hashes_queried{0};
+  std::atomic<uint64_t> hits{0};
+  std::atomic<uint64_t> misses{0};
+  // From tens of microseconds for a single hash to tens of
+  // milliseconds for a large batch.
+  Histogram query_seconds{{1e-5, 2.5e-5, 5e-5, 1e-4, 2.5e-4, 5e-4, 1e-3,
+                           2.5e-3, 5e-3, 1e-2, 2.5e-2, 5e-2}};
+  Histogram hash_seconds{{1e-7, 2.5e-7, 5e-7, 1e-6, 2.5e-6, 5e-6, 1e-5,
+                          2.5e-5, 5e-5, 1e-4}};
+};
+
+extern ServerMetrics metrics;
+
+/** What the health check reports. */
+enum class ServerState {
+  Loading,   // the first load hasn't finished
+  Ready,     // serving, and the last load or reload worked
+  Degraded   // serving, but the last reload failed
+};
+
+boost::program_options::options_description command_line_options();
+boost::program_options::options_description setting_options();
+void read_options(const int argc, const char* const argv[],
+                  boost::program_options::variables_map& vm);
 void log(const LogLevel, const std::string&&);
-void handle_client(boost::asio::ip::tcp::iostream& stream);
-pair64 to_pair64(const std::string&);
-std::string from_pair64(const pair64&);
-bool operator<(const pair64& lhs, const pair64& rhs);
-bool operator==(const pair64& lhs, const pair64& rhs);
-bool operator>(const pair64& lhs, const pair64& rhs);
+bool log_enabled(const LogLevel level);
+bool is_allowed_client(const boost::asio::ip::address& peer);
+void handle_client(std::iostream& stream, const std::string& ipaddr);
+bool handle_plain_client(const int fd, const std::string& ipaddr);
+uint64_t loaded_hash_count();
+uint64_t resident_bytes();
+int run_check(const std::string& host, const uint16_t port,
+              const std::string& input, const std::string& output);
+std::string encode_version(const std::string& version);
+bool is_supported_version(const std::string& version);
+std::string encode_query(const std::vector<std::string>& hashes);
+std::string encode_query_response(const std::vector<bool>& results,
+                                  const char hit = '1', const char miss = '0');
+bool parse_query_response(std::string line, const size_t count,
+                          std::vector<bool>& results, const char hit = '1',
+                          const char miss = '0');
+std::string encode_bye();
+std::string encode_binary_query(const std::vector<pair64>& md5s);
+size_t binary_frame_length(const std::string& bytes);
+size_t decode_binary_query(const std::string& bytes,
+                           std::vector<pair64>& md5s);
+std::string encode_binary_response(const std::vector<bool>& results);
+bool decode_binary_response(const std::string& bytes, const size_t count,
+                            std::vector<bool>& results);
+std::string json_string(const std::string& text);
+void run_benchmark(const std::vector<std::string>& present,
+                   const uint64_t count, const double load_seconds);
+bool read_sample(const std::string& path, std::vector<std::string>& hashes);
+bool run_verify(const std::vector<std::string>& present,
+                const bool misses_matter);
+std::vector<bool> lookup_hashes(std::vector<std::string>::const_iterator first,
+                                std::vector<std::string>::const_iterator last,
+                                const std::string& dataset = "");
+void answer_http(std::iostream& stream,
+                 HttpResponse (*answer)(const HttpRequest&));
+void serve_http(boost::asio::io_service& io_service,
+                boost::asio::ip::tcp::acceptor& acceptor,
+                HttpResponse (*answer)(const HttpRequest&));
+HttpResponse answer_scrape(const HttpRequest& request);
+void serve_metrics(boost::asio::io_service& io_service,
+                   boost::asio::ip::tcp::acceptor& acceptor);
+std::unique_ptr<boost::asio::local::stream_protocol::acceptor>
+open_unix_socket(boost::asio::io_service& io_service, const std::string& path,
+                 const mode_t mode, const unsigned int backlog);
+void serve_admin(boost::asio::io_service& io_service,
+                 boost::asio::local::stream_protocol::acceptor& acceptor);
+void notify_systemd(const std::string& state);
+std::chrono::microseconds systemd_watchdog_interval();
+#ifdef HAVE_HTTP
+void serve_lookups(boost::asio::io_service& io_service,
+                   boost::asio::ip::tcp::acceptor& acceptor);
+#endif
+#ifdef HAVE_OPENSSL
+std::unique_ptr<boost::asio::ssl::context> load_tls_context(
+    const std::string& cert, const std::string& key);
+bool handle_tls_client(boost::asio::ssl::context&, const int fd,
+                       const std::string&);
+#endif
 
 #endif
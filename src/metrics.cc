@@ -0,0 +1,152 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <atomic>
+#include <chrono>
+#include <sstream>
+#include <string>
+#include "main.h"
+
+using boost::asio::ip::tcp;
+using std::string;
+using std::stringstream;
+using std::chrono::duration_cast;
+using std::chrono::seconds;
+using std::chrono::steady_clock;
+
+// defined in main.cc
+extern const steady_clock::time_point& start_time;
+extern const std::atomic<unsigned int>& active_sessions;
+extern const std::atomic<ServerState>& server_state;
+
+ServerMetrics metrics;
+
+Histogram::Histogram(std::vector<double> bounds)
+    : upper{std::move(bounds)},
+      counts{new std::atomic<uint64_t>[upper.size() + 1]} {
+  for (size_t idx = 0; idx <= upper.size(); ++idx) counts[idx] = 0;
+}
+
+void Histogram::observe(const std::chrono::duration<double> elapsed) {
+  size_t idx = 0;
+  while (idx < upper.size() && elapsed.count() > upper[idx]) ++idx;
+  ++counts[idx];
+  ++observations;
+  sum_ns += static_cast<uint64_t>(elapsed.count() * 1e9 + 0.5);
+}
+
+uint64_t Histogram::cumulative(const size_t idx) const {
+  uint64_t rv{0};
+  for (size_t bucket = 0; bucket <= idx && bucket <= upper.size(); ++bucket)
+    rv += counts[bucket];
+  return rv;
+}
+
+namespace {
+/** Writes one metric, with its help and type lines, in Prometheus'
+ * text exposition format.
+ */
+void write_metric(stringstream& out, const char* name, const char* type,
+                  const char* help, const uint64_t value) {
+  out << "# HELP " << name << " " << help << "\n"
+      << "# TYPE " << name << " " << type << "\n"
+      << name << " " << value << "\n";
+}
+
+/** Writes a histogram, with its help and type lines.  Its buckets are
+ * read one at a time while queries carry on, so a scrape can be off by
+ * the odd query in flight.
+ */
+void write_histogram(stringstream& out, const char* name, const char* help,
+                     const Histogram& histogram) {
+  out << "# HELP " << name << " " << help << "\n"
+      << "# TYPE " << name << " histogram\n";
+  const auto& bounds = histogram.bounds();
+  for (size_t idx = 0; idx < bounds.size(); ++idx)
+    out << name << "_bucket{le=\"" << bounds[idx] << "\"} "
+        << histogram.cumulative(idx) << "\n";
+  out << name << "_bucket{le=\"+Inf\"} "
+      << histogram.cumulative(bounds.size()) << "\n"
+      << name << "_sum " << histogram.sum() << "\n"
+      << name << "_count " << histogram.count() << "\n";
+}
+
+string render_metrics() {
+  const auto uptime = duration_cast<seconds>(steady_clock::now() - start_time);
+  stringstream out;
+  write_metric(out, "nsrlsvr_connections_total", "counter",
+               "Clients admitted, not counting any turned away as BUSY.",
+               metrics.connections);
+  write_metric(out, "nsrlsvr_queries_total", "counter",
+               "QUERY commands answered.", metrics.queries);
+  write_metric(out, "nsrlsvr_hashes_queried_total", "counter",
+               "Hashes looked up across all queries.", metrics.hashes_queried);
+  write_metric(out, "nsrlsvr_hits_total", "counter",
+               "Hashes looked up that were in the set.", metrics.hits);
+  write_metric(out, "nsrlsvr_misses_total", "counter",
+               "Hashes looked up that weren't in the set.", metrics.misses);
+  write_histogram(out, "nsrlsvr_query_duration_seconds",
+                  "Time taken to look up each query's hashes.",
+                  metrics.query_seconds);
+  write_histogram(out, "nsrlsvr_hash_duration_seconds",
+                  "Time taken per hash, averaged over each query.",
+                  metrics.hash_seconds);
+  write_metric(out, "nsrlsvr_active_connections", "gauge",
+               "Clients connected, including any waiting for a worker.",
+               active_sessions);
+  write_metric(out, "nsrlsvr_loaded_hashes", "gauge",
+               "Hashes currently being served.", loaded_hash_count());
+  write_metric(out, "nsrlsvr_uptime_seconds", "gauge",
+               "Seconds since nsrlsvr started.",
+               static_cast<uint64_t>(uptime.count()));
+  return out.str();
+}
+
+/** Answers a liveness or readiness probe.  Only a server that's still
+ * loading fails it; one whose last reload failed is still serving the
+ * hashes it had.
+ */
+HttpResponse answer_health() {
+  switch (server_state.load()) {
+    case ServerState::Loading:
+      return HttpResponse(503, "text/plain", "loading\n");
+    case ServerState::Degraded:
+      return HttpResponse(200, "text/plain", "degraded\n");
+    case ServerState::Ready:
+      break;
+  }
+  return HttpResponse(200, "text/plain", "ready\n");
+}
+}  // namespace
+
+/** Answers a request to the metrics port: /metrics for a scrape, or
+ * /health for a probe.
+ */
+HttpResponse answer_scrape(const HttpRequest& request) {
+  if (request.path == "/health" && request.method == "GET")
+    return answer_health();
+  if (request.path != "/metrics" && request.path != "/metrics/")
+    return HttpResponse(404, "text/plain", "not found\n");
+  if (request.method != "GET")
+    return HttpResponse(405, "text/plain", "GET only\n");
+  return HttpResponse(200, "text/plain; version=0.0.4", render_metrics());
+}
+
+/** Answers metrics scrapes on acceptor for as long as the server runs. */
+void serve_metrics(boost::asio::io_service& io_service,
+                   tcp::acceptor& acceptor) {
+  serve_http(io_service, acceptor, answer_scrape);
+}
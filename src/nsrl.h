@@ -0,0 +1,107 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#ifndef NSRL_H
+#define NSRL_H
+
+// The lookup engine on its own, for programs that would rather link it
+// than talk to an nsrlsvr.  Unlike main.h, this needs nothing from
+// Boost.
+
+#include <syslog.h>
+#include <cstdint>
+#include <memory>
+#include <stdexcept>
+#include <string>
+#include <vector>
+
+enum class LogLevel {
+  INFO = LOG_INFO,
+  WARN = LOG_WARNING,
+  DEBUG = LOG_DEBUG,
+  CRITICAL = LOG_CRIT,
+  ALERT = LOG_ALERT,
+  EMERGENCY = LOG_EMERG
+};
+
+/** Why hashes couldn't be loaded.  The message says what happened; the
+ * kind lets the caller say what to do about it.
+ */
+class LoadError : public std::runtime_error {
+ public:
+  enum class Kind {
+    NotFound,
+    PermissionDenied,
+    Unreadable,
+    Corrupt,
+    OutOfMemory
+  };
+
+  LoadError(const Kind kind, const std::string& what)
+      : std::runtime_error(what), reason{kind} {}
+  Kind kind() const { return reason; }
+
+ private:
+  Kind reason;
+};
+
+struct HashData;
+
+/** A set of MD5, SHA-1 and SHA-256 hashes to look things up in, just
+ * as nsrlsvr answers QUERY.  Hashes are matched as the digests they
+ * spell out, so case doesn't matter.  Once made it never changes, and
+ * any number of threads may look things up in it at once; copies share
+ * the same hashes.
+ *
+ * @code
+ * const auto store = HashStore::from_hashes(
+ *     {"8A8B5E9F4D64E4E8C4C1B3AA493F8E5E",
+ *      "da39a3ee5e6b4b0d3255bfef95601890afd80709"});
+ * store.contains("8a8b5e9f4d64e4e8c4c1b3aa493f8e5e");  // true
+ * store.query({"DA39A3EE5E6B4B0D3255BFEF95601890AFD80709",
+ *              "00000000000000000000000000000000", "not a hash"});
+ * // {true, false, false}
+ * @endcode
+ * tests/nsrl_example.cc runs this example as a test.
+ */
+class HashStore {
+ public:
+  /** Loads a hash file the way nsrlsvr's --file does: one hash per
+   * line, an RDS NSRLFile.txt, an RDSv3 SQLite database, or any of
+   * them gzipped, as the build allows.
+   * @throws LoadError if the file can't be read or isn't hashes, or if
+   * memory or threads run out while loading it; nothing else
+   */
+  static HashStore load(const std::string& path);
+  /** Makes a store of the given hashes, in hex.
+   * @throws std::invalid_argument if one isn't an MD5, SHA-1 or SHA-256
+   * @throws LoadError if memory or threads run out
+   */
+  static HashStore from_hashes(const std::vector<std::string>& hashes);
+
+  /** Whether hash is in the store.  Anything that isn't a hash isn't. */
+  bool contains(const std::string& hash) const;
+  /** contains() for each of hashes, in order. */
+  std::vector<bool> query(const std::vector<std::string>& hashes) const;
+  uint64_t size() const;
+
+ private:
+  explicit HashStore(std::shared_ptr<const HashData> hashes);
+
+  std::shared_ptr<const HashData> data;
+};
+
+#endif
@@ -0,0 +1,209 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <boost/program_options.hpp>
+#include <fstream>
+#include <stdexcept>
+#include <string>
+#include <vector>
+#include "main.h"
+
+using boost::program_options::bool_switch;
+using boost::program_options::options_description;
+using boost::program_options::parse_command_line;
+using boost::program_options::parse_config_file;
+using boost::program_options::store;
+using boost::program_options::value;
+using boost::program_options::variables_map;
+using std::ifstream;
+using std::runtime_error;
+using std::string;
+using std::vector;
+
+/** Options that only make sense on the command line. */
+options_description command_line_options() {
+  // clang-format off
+  options_description generic{"nsrlsvr options"};
+  generic.add_options()
+    ("help,h", "Help screen")
+    ("version,v", "Display package version")
+    ("bug-report,b", "Display bug reporting information")
+    ("config,c", value<string>(), "read settings from a configuration file")
+    ("check", bool_switch(),
+     "look up hashes from standard input on a running server, then exit")
+    ("host", value<string>()->default_value("localhost"),
+     "server for --check to query")
+    ("input", value<string>(),
+     "file for --check to read hashes from instead of standard input")
+    ("output", value<string>(),
+     "CSV file for --check to write results to");
+  // clang-format on
+  return generic;
+}
+
+/** Options that may also come from a --config file, where each is
+ * written as NAME = VALUE.
+ */
+options_description setting_options() {
+  // clang-format off
+  options_description settings{"settings"};
+  settings.add_options()
+    ("file,f", value<vector<string>>()->default_value(
+       vector<string>{PKGDATADIR "/hashes.txt"}, PKGDATADIR "/hashes.txt"),
+     "hash file (repeat to merge several)")
+    ("dataset", value<vector<string>>(),
+     "NAME=FILE: serve FILE as its own dataset, which clients select with "
+     "DATASET NAME (repeat for more files or datasets)")
+    ("port,p", value<uint16_t>()->default_value(9120), "port")
+    ("metrics-port", value<uint16_t>()->default_value(0),
+     "serve Prometheus metrics over HTTP on this port (0 = off)")
+    ("http-port", value<uint16_t>()->default_value(0),
+     "answer lookups over HTTP on this port (0 = off)")
+    ("unix-socket", value<string>(),
+     "also serve clients on a Unix socket at this path")
+    ("admin-socket", value<string>(),
+     "take ADD and DEL commands on a Unix socket at this path")
+    ("bind-address", value<string>()->default_value("::"),
+     "address to listen on")
+    ("dual-stack", value<bool>()->default_value(true)->implicit_value(true),
+     "let an IPv6 listener accept IPv4 clients too")
+    ("allow", value<vector<string>>(),
+     "only let in clients from this address or CIDR range (repeatable)")
+    ("dry-run", bool_switch(), "test configuration")
+    ("allow-empty", bool_switch(),
+     "start even if no hashes were loaded, instead of failing")
+    ("expect-hashes", value<uint64_t>(),
+     "complain if the load doesn't come to this many hashes")
+    ("expect-tolerance", value<double>()->default_value(0),
+     "percentage --expect-hashes may be off by")
+    ("strict", bool_switch(),
+     "refuse to start, rather than warn, if --expect-hashes isn't met")
+    ("rds-sha1", bool_switch(),
+     "serve an NSRLFile.txt's SHA-1s as well as its MD5s")
+    ("invert", bool_switch(),
+     "answer QUERY with 0 for a hash that's present and 1 for one that isn't")
+    ("hit-char", value<string>()->default_value("1"),
+     "character QUERY answers with in place of 1")
+    ("miss-char", value<string>()->default_value("0"),
+     "character QUERY answers with in place of 0")
+    ("bloom", bool_switch(),
+     "answer queries from a Bloom filter instead of the hash set")
+    ("bloom-fpr", value<double>()->default_value(1e-6),
+     "Bloom filter false-positive rate")
+    ("bloom-verify", bool_switch(),
+     "with --bloom and --index, check each hit against the index")
+    ("track-sources", bool_switch(),
+     "remember which file each hash came from, for QUERYSOURCE")
+    ("compact", bool_switch(),
+     "pack MD5s into about 15% less memory")
+    ("shard-bits", value<unsigned int>()->default_value(0),
+     "search MD5s in 2^N shards by their leading bits (0 = one array)")
+    ("nearest", bool_switch(),
+     "allow NEAREST, which scans every hash for near misses")
+    ("watch", bool_switch(),
+     "reload when the hash files change, as well as on SIGHUP")
+    ("watch-settle", value<unsigned int>()->default_value(5),
+     "seconds a changed hash file must stay the same before --watch "
+     "reloads it")
+    ("load-timeout", value<unsigned int>()->default_value(0),
+     "give up if the first load takes more than this many seconds "
+     "(0 = never)")
+    ("progress-interval", value<uint64_t>()->default_value(1000000),
+     "log progress every this many hashes while loading (0 = never)")
+    ("index", value<string>(), "serve MD5s from a prebuilt index file")
+    ("madvise", value<string>()->default_value("random"),
+     "how the --index will be read: random, sequential, willneed or normal")
+    ("sha1-md5-map", value<string>(),
+     "answer for SHA-1s by looking up the MD5s this file pairs them with")
+    ("build-index", value<string>(),
+     "write the hash file's MD5s to an index file and exit")
+    ("bench", bool_switch(),
+     "load the hashes, time a batch of lookups, print a summary and exit")
+    ("bench-count", value<uint64_t>()->default_value(1000000),
+     "how many lookups --bench times")
+    ("verify", bool_switch(),
+     "load the hashes, check that known ones hit and random ones miss, "
+     "print PASS or FAIL and exit")
+    ("sample", value<string>(),
+     "file of hashes known to be loaded, for --verify to look up")
+    ("tls-cert", value<string>(), "PEM certificate chain for TLS")
+    ("tls-key", value<string>(), "PEM private key for TLS")
+    ("pid-file", value<string>(), "write the server's PID to this file")
+    ("umask", value<string>()->default_value("022"),
+     "umask, in octal, for the files and --unix-socket nsrlsvr creates")
+    ("log-file", value<string>(), "append log messages here, not to syslog")
+    ("log-format", value<string>()->default_value("text"),
+     "write --log-file and standard error lines as text or json")
+    ("log-level", value<string>()->default_value("info"),
+     "least urgent messages to log: emergency, alert, critical, warn, info "
+     "or debug")
+    ("user", value<string>(), "account to run as once the port is bound")
+    ("group", value<string>(),
+     "group to run as once the port is bound (default: the user's)")
+    ("max-connections", value<unsigned int>()->default_value(256),
+     "most clients to serve at once")
+    ("workers", value<unsigned int>(),
+     "clients to serve simultaneously (default: one per CPU)")
+    ("tcp-keepalive", value<unsigned int>()->default_value(0),
+     "probe connections idle this many seconds (0 = off)")
+    ("backlog", value<unsigned int>()->default_value(1024),
+     "connections the kernel may hold for us before we accept them")
+    ("loading-behavior", value<string>()->default_value("refuse"),
+     "what clients connecting mid-load get: refuse, reject or hold")
+    ("accept-queue", value<unsigned int>()->default_value(0),
+     "most clients to keep waiting for a worker (0 = no limit)")
+    ("idle-timeout", value<unsigned int>()->default_value(300),
+     "seconds a client may stay silent before it's dropped (0 = never)")
+    ("max-query-hashes", value<unsigned int>()->default_value(10000),
+     "most hashes one QUERY may ask about (0 = no limit)")
+    ("max-line-bytes", value<size_t>()->default_value(1048576),
+     "longest line a client may send before it's cut off (0 = no limit)")
+    ("cache-size", value<size_t>()->default_value(0),
+     "remember the answers for this many recently queried hashes (0 = off)")
+    ("rate-limit", value<double>()->default_value(0),
+     "QUERY commands allowed per second from each address (0 = no limit)")
+    ("rate-burst", value<double>(),
+     "QUERY commands an address may send at once (default: the rate)");
+  // clang-format on
+  return settings;
+}
+
+/** Stores the command line in vm, then the settings in the --config
+ * file it names, if it names one.  program_options never overwrites a
+ * value once it's been stored, so the command line takes precedence
+ * over the config file, and both take precedence over the defaults.
+ * @throws boost::program_options::error if the command line is bad
+ * @throws std::runtime_error if the config file can't be read, or if
+ * a setting in it is malformed or unknown
+ */
+void read_options(const int argc, const char* const argv[],
+                  variables_map& vm) {
+  const auto settings = setting_options();
+  options_description options;
+  options.add(command_line_options()).add(settings);
+  store(parse_command_line(argc, argv, options), vm);
+  if (!vm.count("config")) return;
+
+  const auto config_path = vm["config"].as<string>();
+  ifstream config_file{config_path.c_str()};
+  if (not config_file)
+    throw runtime_error("Could not open " + config_path + " for reading.");
+  try {
+    store(parse_config_file(config_file, settings), vm);
+  } catch (boost::program_options::error& e) {
+    throw runtime_error(config_path + ": " + e.what());
+  }
+}
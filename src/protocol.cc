@@ -0,0 +1,188 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+
+// The wire format of the query protocol, shared by the server and by
+// --check.  Nothing here does any I/O.
+
+#include <regex>
+#include <string>
+#include <vector>
+#include "main.h"
+
+using std::regex;
+using std::regex_match;
+using std::string;
+using std::vector;
+
+/** The VERSION: command asking for the given protocol version. */
+string encode_version(const string& version) {
+  return "VERSION: " + version + "\r\n";
+}
+
+/** nsrlsvr speaks protocol 1.x and 2.0; anything else, including a
+ * malformed version, is refused.
+ */
+bool is_supported_version(const string& version) {
+  static const regex version_re{"^(1\\.[0-9]+|2\\.0)$"};
+  return regex_match(version, version_re);
+}
+
+/** A QUERY command asking about each of hashes. */
+string encode_query(const vector<string>& hashes) {
+  string rv{"QUERY"};
+  for (const auto& hash : hashes) rv += " " + hash;
+  return rv + "\r\n";
+}
+
+/** The text answer to a QUERY, one character per hash. */
+string encode_query_response(const vector<bool>& results, const char hit,
+                             const char miss) {
+  string rv{"OK "};
+  for (const auto found : results) rv += found ? hit : miss;
+  return rv + "\r\n";
+}
+
+/** Reads the text answer to a QUERY about count hashes into results.
+ * A trailing line ending is ignored.
+ * @return false if line isn't an answer to that query
+ */
+bool parse_query_response(string line, const size_t count,
+                          vector<bool>& results, const char hit,
+                          const char miss) {
+  if (!line.empty() && '\n' == line.back()) line.pop_back();
+  if (!line.empty() && '\r' == line.back()) line.pop_back();
+  if (0 != line.compare(0, 3, "OK ") || line.size() != count + 3)
+    return false;
+
+  vector<bool> rv;
+  rv.reserve(count);
+  for (auto iter = line.cbegin() + 3; iter != line.cend(); ++iter) {
+    if (hit != *iter && miss != *iter) return false;
+    rv.push_back(hit == *iter);
+  }
+  results.swap(rv);
+  return true;
+}
+
+// After FORMAT: binary, a client sends frames instead of lines: a
+// 4-byte count of hashes, most significant byte first, then that many
+// 16-byte MD5s as raw digests.  Each frame is answered with one bit per
+// hash, set for a hit, packed most significant bit first and padded
+// out to a whole byte.  A frame of no hashes ends the session.
+
+namespace {
+void put_u64(string& out, const unsigned long long value) {
+  for (int shift = 56; shift >= 0; shift -= 8)
+    out.push_back(static_cast<char>((value >> shift) & 0xFF));
+}
+
+unsigned long long get_u64(const string& in, const size_t offset) {
+  unsigned long long rv{0};
+  for (size_t idx = 0; idx < 8; ++idx)
+    rv = (rv << 8) | static_cast<unsigned char>(in[offset + idx]);
+  return rv;
+}
+}  // namespace
+
+/** A FORMAT: binary frame asking about each of md5s. */
+string encode_binary_query(const vector<pair64>& md5s) {
+  string rv;
+  rv.reserve(4 + 16 * md5s.size());
+  const auto count = static_cast<uint32_t>(md5s.size());
+  for (int shift = 24; shift >= 0; shift -= 8)
+    rv.push_back(static_cast<char>((count >> shift) & 0xFF));
+  for (const auto& md5 : md5s) {
+    put_u64(rv, md5.first);
+    put_u64(rv, md5.second);
+  }
+  return rv;
+}
+
+/** How long the FORMAT: binary frame starting bytes is, going by the
+ * count at its head, or 0 if bytes is too short to hold the count.
+ */
+size_t binary_frame_length(const string& bytes) {
+  if (bytes.size() < 4) return 0;
+  uint32_t count{0};
+  for (size_t idx = 0; idx < 4; ++idx)
+    count = (count << 8) | static_cast<unsigned char>(bytes[idx]);
+  return 4 + 16 * static_cast<size_t>(count);
+}
+
+/** Reads the FORMAT: binary frame at the start of bytes into md5s.
+ * @return the length of the frame, or 0, leaving md5s alone, if bytes
+ * holds only part of one
+ */
+size_t decode_binary_query(const string& bytes, vector<pair64>& md5s) {
+  const auto length = binary_frame_length(bytes);
+  if (0 == length || bytes.size() < length) return 0;
+  vector<pair64> rv;
+  rv.reserve((length - 4) / 16);
+  for (size_t offset = 4; offset < length; offset += 16)
+    rv.emplace_back(get_u64(bytes, offset), get_u64(bytes, offset + 8));
+  md5s.swap(rv);
+  return length;
+}
+
+/** The FORMAT: binary answer to a frame, one bit per hash. */
+string encode_binary_response(const vector<bool>& results) {
+  string rv((results.size() + 7) / 8, '\0');
+  for (size_t idx = 0; idx < results.size(); ++idx)
+    if (results[idx])
+      rv[idx / 8] = static_cast<char>(rv[idx / 8] | (0x80 >> (idx % 8)));
+  return rv;
+}
+
+/** Reads the FORMAT: binary answer to a frame of count hashes into
+ * results.
+ * @return false if bytes isn't that answer's length
+ */
+bool decode_binary_response(const string& bytes, const size_t count,
+                            vector<bool>& results) {
+  if (bytes.size() != (count + 7) / 8) return false;
+  vector<bool> rv;
+  rv.reserve(count);
+  for (size_t idx = 0; idx < count; ++idx)
+    rv.push_back(0 != (static_cast<unsigned char>(bytes[idx / 8]) &
+                       (0x80 >> (idx % 8))));
+  results.swap(rv);
+  return true;
+}
+
+/** Quotes text, which may have come from a client, as a JSON string. */
+string json_string(const string& text) {
+  static const char hex[] = "0123456789abcdef";
+  string rv{"\""};
+  for (const auto ch : text) {
+    const auto byte = static_cast<unsigned char>(ch);
+    if ('"' == ch || '\\' == ch) {
+      rv += '\\';
+      rv += ch;
+    } else if (byte < 0x20) {
+      rv += "\\u00";
+      rv += hex[byte >> 4];
+      rv += hex[byte & 0xf];
+    } else {
+      rv += ch;
+    }
+  }
+  rv += '"';
+  return rv;
+}
+
+/** The command ending a session. */
+string encode_bye() { return "BYE\r\n"; }
@@ -0,0 +1,71 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+
+#include "main.h"
+
+using std::string;
+
+QueryCache::QueryCache(const size_t capacity) : capacity{capacity} {}
+
+/** Looks up what hash was last found to be in data.
+ * @return false if the cache doesn't know
+ */
+bool QueryCache::find(const std::shared_ptr<const HashData>& data,
+                      const string& hash, bool& found) {
+  std::lock_guard<std::mutex> lock(mutex);
+  switch_to(data);
+  const auto entry = index.find(hash);
+  if (index.end() == entry) return false;
+  entries.splice(entries.begin(), entries, entry->second);
+  found = entry->second->second;
+  return true;
+}
+
+/** Remembers whether hash is in data, forgetting the least recently
+ * used entry if the cache is full.
+ */
+void QueryCache::insert(const std::shared_ptr<const HashData>& data,
+                        const string& hash, const bool found) {
+  std::lock_guard<std::mutex> lock(mutex);
+  switch_to(data);
+  const auto entry = index.find(hash);
+  if (index.end() != entry) {
+    entry->second->second = found;
+    entries.splice(entries.begin(), entries, entry->second);
+    return;
+  }
+  if (entries.size() >= capacity) {
+    index.erase(entries.back().first);
+    entries.pop_back();
+  }
+  entries.emplace_front(hash, found);
+  index.emplace(hash, entries.begin());
+}
+
+// A lookup that started before a reload can still be running after
+// it, so a clear() at reload time wouldn't be enough: it could put an
+// old answer back right afterwards.  Instead every entry belongs to
+// owner, and data that isn't owner starts the cache over.  owner is a
+// weak_ptr so the cache doesn't keep old data alive.
+void QueryCache::switch_to(const std::shared_ptr<const HashData>& data) {
+  if (!owner.owner_before(data) && !data.owner_before(owner) &&
+      !owner.expired())
+    return;
+  owner = data;
+  entries.clear();
+  index.clear();
+}
@@ -0,0 +1,61 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <algorithm>
+#include "main.h"
+
+using std::chrono::duration;
+using std::chrono::seconds;
+using std::chrono::steady_clock;
+
+namespace {
+/** How often allow() looks for buckets it can throw away. */
+const seconds sweep_interval{60};
+}  // namespace
+
+RateLimiter::RateLimiter(const double rate, const double burst)
+    : rate{rate}, burst{burst}, last_sweep{steady_clock::now()} {}
+
+/** Takes a token from peer's bucket, if it has one to spare. */
+bool RateLimiter::allow(const std::string& peer) {
+  const auto now = steady_clock::now();
+  std::lock_guard<std::mutex> lock(mutex);
+  if (now - last_sweep >= sweep_interval) evict_idle(now);
+
+  auto found = buckets.find(peer);
+  if (found == buckets.end())
+    found = buckets.emplace(peer, Bucket{burst, now}).first;
+  auto& bucket = found->second;
+  const duration<double> elapsed = now - bucket.updated;
+  bucket.tokens = std::min(burst, bucket.tokens + elapsed.count() * rate);
+  bucket.updated = now;
+  if (bucket.tokens < 1.0) return false;
+  bucket.tokens -= 1.0;
+  return true;
+}
+
+// A bucket that's been left alone long enough to refill completely is
+// no different from a brand new one, so dropping it loses nothing.
+void RateLimiter::evict_idle(const steady_clock::time_point now) {
+  const duration<double> refill{burst / rate};
+  for (auto iter = buckets.begin(); iter != buckets.end();) {
+    if (now - iter->second.updated >= refill)
+      iter = buckets.erase(iter);
+    else
+      ++iter;
+  }
+  last_sweep = now;
+}
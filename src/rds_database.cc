@@ -0,0 +1,82 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include "engine.h"
+
+#ifdef HAVE_SQLITE3
+
+#include <sqlite3.h>
+#include <stdexcept>
+#include <string>
+#include <vector>
+
+using std::runtime_error;
+using std::string;
+using std::vector;
+
+namespace {
+/** Runs a single-column query and converts every non-empty value it
+ * returns into a hash, appending the results to dest.
+ */
+template <typename T, typename Converter>
+void read_column(sqlite3* db, const char* sql, vector<T>& dest,
+                 Converter convert) {
+  sqlite3_stmt* stmt{nullptr};
+  if (SQLITE_OK != sqlite3_prepare_v2(db, sql, -1, &stmt, nullptr))
+    throw runtime_error(sqlite3_errmsg(db));
+
+  int rc;
+  try {
+    while (SQLITE_ROW == (rc = sqlite3_step(stmt))) {
+      const auto text = sqlite3_column_text(stmt, 0);
+      if (nullptr == text || '\0' == text[0]) continue;
+      dest.emplace_back(convert(string(reinterpret_cast<const char*>(text))));
+    }
+  } catch (...) {
+    sqlite3_finalize(stmt);
+    throw;
+  }
+  sqlite3_finalize(stmt);
+
+  if (SQLITE_DONE != rc) throw runtime_error(sqlite3_errmsg(db));
+}
+}  // namespace
+
+void load_rds_database(const string& path, vector<pair64>& md5s,
+                       vector<sha1_digest>& sha1s,
+                       vector<sha256_digest>& sha256s) {
+  sqlite3* db{nullptr};
+  if (SQLITE_OK !=
+      sqlite3_open_v2(path.c_str(), &db, SQLITE_OPEN_READONLY, nullptr)) {
+    const string msg{db ? sqlite3_errmsg(db) : "out of memory"};
+    sqlite3_close(db);
+    throw runtime_error(msg);
+  }
+
+  // The same file shows up in the RDS once per package that ships it,
+  // so ask SQLite for each distinct hash rather than every row.
+  try {
+    read_column(db, "SELECT DISTINCT md5 FROM FILE", md5s, to_pair64);
+    read_column(db, "SELECT DISTINCT sha1 FROM FILE", sha1s, to_sha1);
+    read_column(db, "SELECT DISTINCT sha256 FROM FILE", sha256s, to_sha256);
+  } catch (...) {
+    sqlite3_close(db);
+    throw;
+  }
+  sqlite3_close(db);
+}
+
+#endif
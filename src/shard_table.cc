@@ -0,0 +1,50 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+
+#include <algorithm>
+#include <stdexcept>
+#include "engine.h"
+
+// The top shard_bits bits of an MD5's first half pick its shard, and
+// starts[n] is how many hashes sort before shard n, so shard n is
+// [starts[n], starts[n + 1]).  MD5s are uniform enough that the shards
+// come out close to the same size.
+
+ShardTable::ShardTable(const HashView<pair64>& sorted, const unsigned bits)
+    : shard_bits{bits} {
+  if (0 == bits || bits > 24)
+    throw std::invalid_argument("shards must take 1 to 24 bits");
+  starts.reserve((1ULL << bits) + 1);
+  auto first = sorted.cbegin();
+  for (uint64_t idx = 0; idx < (1ULL << bits); ++idx) {
+    first = std::lower_bound(first, sorted.cend(),
+                             pair64{idx << (64 - bits), 0});
+    starts.push_back(static_cast<uint64_t>(first - sorted.cbegin()));
+  }
+  starts.push_back(sorted.size());
+}
+
+uint64_t ShardTable::shard(const pair64& key) const {
+  return key.first >> (64 - shard_bits);
+}
+
+bool ShardTable::contains(const HashView<pair64>& sorted,
+                          const pair64& key) const {
+  const auto idx = shard(key);
+  return std::binary_search(sorted.cbegin() + starts[idx],
+                            sorted.cbegin() + starts[idx + 1], key);
+}
@@ -0,0 +1,95 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <errno.h>
+#include <sys/socket.h>
+#include <iostream>
+#include "main.h"
+
+using std::string;
+
+long PlainSocketBuffer::receive(char* buffer, const std::size_t len) {
+  for (;;) {
+    const auto count = recv(fd, buffer, len, 0);
+    if (0 <= count) return count;
+    if (EINTR == errno) continue;
+    if (EAGAIN == errno || EWOULDBLOCK == errno) idle = true;
+    note_error();
+    return -1;
+  }
+}
+
+bool PlainSocketBuffer::transmit(const char* buffer, const std::size_t len) {
+  std::size_t sent{0};
+  while (sent < len) {
+    const auto count = send(fd, buffer + sent, len - sent, MSG_NOSIGNAL);
+    if (0 > count && EINTR == errno) continue;
+    if (0 > count) {
+      note_error();
+      return false;
+    }
+    sent += static_cast<std::size_t>(count);
+  }
+  return true;
+}
+
+SocketBuffer::SocketBuffer() {
+  setg(input.data(), input.data(), input.data());
+  setp(output.data(), output.data() + output.size());
+}
+
+SocketBuffer::int_type SocketBuffer::underflow() {
+  if (gptr() < egptr()) return traits_type::to_int_type(*gptr());
+  // The client may be waiting on the replies so far before it sends
+  // any more, so send them before waiting on it.
+  if (0 != sync()) return traits_type::eof();
+  const auto count = receive(input.data(), input.size());
+  if (0 >= count) return traits_type::eof();
+  setg(input.data(), input.data(), input.data() + count);
+  return traits_type::to_int_type(*gptr());
+}
+
+SocketBuffer::int_type SocketBuffer::overflow(int_type ch) {
+  if (0 != sync()) return traits_type::eof();
+  if (!traits_type::eq_int_type(ch, traits_type::eof())) {
+    *pptr() = traits_type::to_char_type(ch);
+    pbump(1);
+  }
+  return traits_type::not_eof(ch);
+}
+
+void SocketBuffer::note_error() {
+  if (EPIPE == errno || ECONNRESET == errno) gone = true;
+}
+
+int SocketBuffer::sync() {
+  const auto pending = static_cast<std::size_t>(pptr() - pbase());
+  const bool sent = 0 == pending || transmit(pbase(), pending);
+  setp(output.data(), output.data() + output.size());
+  return sent ? 0 : -1;
+}
+
+/** Runs a session over an unencrypted connection.
+ * @return whether the session ended because the client went idle
+ */
+bool handle_plain_client(const int fd, const string& ipaddr) {
+  PlainSocketBuffer buffer(fd);
+  std::iostream stream(&buffer);
+  handle_client(stream, ipaddr);
+  if (buffer.client_left())
+    log(LogLevel::DEBUG, ipaddr + " went away partway through the session");
+  return buffer.timed_out();
+}
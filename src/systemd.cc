@@ -0,0 +1,64 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <sys/socket.h>
+#include <sys/un.h>
+#include <unistd.h>
+#include <cstddef>
+#include <cstdlib>
+#include <cstring>
+#include <string>
+#include "main.h"
+
+using std::string;
+using std::chrono::microseconds;
+
+/** Sends a state change such as "READY=1" to systemd, speaking the
+ * sd_notify(3) protocol directly so there's no libsystemd dependency.
+ * Does nothing unless systemd passed us $NOTIFY_SOCKET.
+ */
+void notify_systemd(const string& state) {
+  const char* path = getenv("NOTIFY_SOCKET");
+  if (nullptr == path || ('/' != path[0] && '@' != path[0])) return;
+
+  sockaddr_un address;
+  std::memset(&address, 0, sizeof(address));
+  address.sun_family = AF_UNIX;
+  const auto length = std::strlen(path);
+  if (length >= sizeof(address.sun_path)) return;
+  std::memcpy(address.sun_path, path, length);
+  // A leading '@' names a socket in the abstract namespace.
+  if ('@' == address.sun_path[0]) address.sun_path[0] = '\0';
+
+  const int fd = socket(AF_UNIX, SOCK_DGRAM | SOCK_CLOEXEC, 0);
+  if (0 > fd) return;
+  sendto(fd, state.c_str(), state.size(), MSG_NOSIGNAL,
+         reinterpret_cast<const sockaddr*>(&address),
+         static_cast<socklen_t>(offsetof(sockaddr_un, sun_path) + length));
+  close(fd);
+}
+
+/** How often systemd wants WATCHDOG=1 from us, or zero if WatchdogSec
+ * isn't set for this process.
+ */
+microseconds systemd_watchdog_interval() {
+  const char* usec = getenv("WATCHDOG_USEC");
+  if (nullptr == usec) return microseconds(0);
+  const char* pid = getenv("WATCHDOG_PID");
+  if (nullptr != pid && std::strtol(pid, nullptr, 10) != getpid())
+    return microseconds(0);
+  return microseconds(std::strtoull(usec, nullptr, 10));
+}
@@ -0,0 +1,122 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include "main.h"
+
+#ifdef HAVE_OPENSSL
+
+#include <openssl/ssl.h>
+#include <iostream>
+#include <memory>
+#include <stdexcept>
+#include <string>
+
+using boost::asio::ssl::context;
+using std::runtime_error;
+using std::string;
+using std::unique_ptr;
+
+namespace {
+/** A SocketBuffer over an established TLS session.  OpenSSL does the
+ * socket I/O itself, so the socket's receive timeout still applies.
+ */
+class TlsSocketBuffer : public SocketBuffer {
+ public:
+  explicit TlsSocketBuffer(SSL* session) : session{session} {}
+
+ protected:
+  long receive(char* buffer, const std::size_t len) override {
+    const auto count = SSL_read(session, buffer, static_cast<int>(len));
+    if (0 < count) return count;
+    switch (SSL_get_error(session, count)) {
+      case SSL_ERROR_ZERO_RETURN:
+        return 0;
+      case SSL_ERROR_WANT_READ:
+        // The socket blocks, so the only way to get here is a timeout.
+        idle = true;
+        return -1;
+      case SSL_ERROR_SYSCALL:
+        note_error();
+        return -1;
+      default:
+        return -1;
+    }
+  }
+
+  bool transmit(const char* buffer, const std::size_t len) override {
+    if (0 == len) return true;
+    const auto count = SSL_write(session, buffer, static_cast<int>(len));
+    if (0 < count) return true;
+    if (SSL_ERROR_SYSCALL == SSL_get_error(session, count)) note_error();
+    return false;
+  }
+
+ private:
+  SSL* session;
+};
+}  // namespace
+
+/** Loads a PEM certificate chain and private key for serving TLS.
+ * Throws runtime_error describing whichever part couldn't be used.
+ */
+unique_ptr<context> load_tls_context(const string& cert, const string& key) {
+  unique_ptr<context> rv{new context(context::sslv23_server)};
+  rv->set_options(context::default_workarounds | context::no_sslv2 |
+                  context::no_sslv3 | context::no_tlsv1 |
+                  context::no_tlsv1_1);
+
+  boost::system::error_code ec;
+  rv->use_certificate_chain_file(cert, ec);
+  if (ec)
+    throw runtime_error("couldn't load TLS certificate " + cert + ": " +
+                        ec.message());
+  rv->use_private_key_file(key, context::pem, ec);
+  if (ec)
+    throw runtime_error("couldn't load TLS key " + key + ": " +
+                        ec.message());
+  if (1 != SSL_CTX_check_private_key(rv->native_handle()))
+    throw runtime_error("TLS key " + key + " doesn't match certificate " +
+                        cert);
+  return rv;
+}
+
+/** Runs a session over TLS on an accepted connection.
+ * @return whether the session ended because the client went idle
+ */
+bool handle_tls_client(context& ctx, const int fd, const string& ipaddr) {
+  unique_ptr<SSL, decltype(&SSL_free)> session{SSL_new(ctx.native_handle()),
+                                               SSL_free};
+  if (!session || 1 != SSL_set_fd(session.get(), fd)) {
+    log(LogLevel::ALERT, "couldn't set up TLS for " + ipaddr);
+    return false;
+  }
+  if (1 != SSL_accept(session.get())) {
+    log(LogLevel::ALERT, ipaddr + " failed TLS handshake");
+    return false;
+  }
+
+  TlsSocketBuffer buffer(session.get());
+  std::iostream stream(&buffer);
+  handle_client(stream, ipaddr);
+  if (buffer.client_left())
+    log(LogLevel::DEBUG, ipaddr + " went away partway through the session");
+
+  // The client may already be gone, so a failed close_notify is fine.
+  SSL_shutdown(session.get());
+  return buffer.timed_out();
+}
+
+#endif
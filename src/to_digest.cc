@@ -0,0 +1,49 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <array>
+#include <cctype>
+#include <stdexcept>
+#include "engine.h"
+
+using std::array;
+using std::invalid_argument;
+using std::string;
+
+namespace {
+unsigned char nibble(const char ch) {
+  if (ch >= '0' && ch <= '9') return static_cast<unsigned char>(ch - '0');
+  if (ch >= 'A' && ch <= 'F') return static_cast<unsigned char>(ch - 'A' + 10);
+  if (ch >= 'a' && ch <= 'f') return static_cast<unsigned char>(ch - 'a' + 10);
+  throw invalid_argument("not a hash");
+}
+
+/** Decodes a string of exactly N*2 hexadecimal digits into N bytes. */
+template <size_t N>
+array<unsigned char, N> to_bytes(const string& input) {
+  if (input.size() != N * 2) throw invalid_argument("not a hash");
+
+  array<unsigned char, N> rv;
+  for (size_t idx = 0; idx < N; ++idx)
+    rv[idx] = static_cast<unsigned char>((nibble(input[idx * 2]) << 4) |
+                                         nibble(input[idx * 2 + 1]));
+  return rv;
+}
+}  // namespace
+
+sha1_digest to_sha1(const string& input) { return to_bytes<20>(input); }
+
+sha256_digest to_sha256(const string& input) { return to_bytes<32>(input); }
@@ -20,7 +20,7 @@ OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
 #include <regex>
 #include <sstream>
 #include <stdexcept>
-#include "main.h"
+#include "engine.h"
 
 using std::hex;
 using std::invalid_argument;
@@ -35,8 +35,10 @@ using std::stringstream;
 
 string from_pair64(const pair64& input) {
   stringstream stream;
-  stream << setfill('0') << setw(sizeof(unsigned long long) * 2) << hex
-         << input.first << input.second;
+  // setw() only lasts for one value, so each half needs its own.
+  stream << setfill('0') << hex << setw(sizeof(unsigned long long) * 2)
+         << input.first << setw(sizeof(unsigned long long) * 2)
+         << input.second;
   return string(stream.str());
 }
 
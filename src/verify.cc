@@ -0,0 +1,90 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <cstddef>
+#include <fstream>
+#include <iostream>
+#include <random>
+#include <sstream>
+#include <string>
+#include <vector>
+#include "main.h"
+
+using std::cout;
+using std::string;
+using std::vector;
+
+namespace {
+/** How many random MD5s --verify expects to miss. */
+const size_t random_count{100};
+}  // namespace
+
+/** Reads the hashes in a --sample file, one or more to a line.
+ * Anything from a # to the end of its line is a comment, as for
+ * --check.
+ * @return false if the file couldn't be read
+ */
+bool read_sample(const string& path, vector<string>& hashes) {
+  std::ifstream infile{path};
+  if (!infile) return false;
+  string line;
+  while (getline(infile, line)) {
+    std::istringstream words{line.substr(0, line.find('#'))};
+    string hash;
+    while (words >> hash) hashes.push_back(hash);
+  }
+  return !infile.bad();
+}
+
+/** Looks up each of present, which should all be loaded, along with
+ * some random MD5s, which should all miss, through the same path QUERY
+ * uses.  Prints each hash that came out wrong, then PASS or FAIL.
+ * @param misses_matter false if a random MD5 can hit by chance, as
+ * under --bloom; such hits are then printed but don't fail the check
+ * @return true for PASS
+ */
+bool run_verify(const vector<string>& present, const bool misses_matter) {
+  std::mt19937_64 rng{std::random_device{}()};
+  vector<string> absent;
+  absent.reserve(random_count);
+  while (absent.size() < random_count)
+    absent.push_back(from_pair64(pair64{rng(), rng()}));
+
+  uint64_t found{0};
+  const auto hits = lookup_hashes(present.cbegin(), present.cend());
+  for (size_t idx = 0; idx < present.size(); ++idx) {
+    if (hits[idx])
+      ++found;
+    else
+      cout << "missing: " << present[idx] << "\n";
+  }
+  uint64_t stray{0};
+  const auto misses = lookup_hashes(absent.cbegin(), absent.cend());
+  for (size_t idx = 0; idx < absent.size(); ++idx) {
+    if (!misses[idx]) continue;
+    ++stray;
+    cout << "unexpected hit: " << absent[idx] << "\n";
+  }
+
+  // With nothing known to be present, a dataset that is all misses
+  // would pass.
+  const bool passed = !present.empty() && found == present.size() &&
+                      (0 == stray || !misses_matter);
+  cout << (passed ? "PASS" : "FAIL") << ": " << found << " of "
+       << present.size() << " known hashes found, " << stray << " of "
+       << absent.size() << " random hashes found\n";
+  return passed;
+}
@@ -0,0 +1,150 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+// Checks that a Bloom filter never reports a hash it was given as
+// absent, whatever rate it's sized for, and that with --bloom-verify
+// the index behind it turns away the filter's false positives.
+
+#include <algorithm>
+#include <cstdlib>
+#include <iostream>
+#include <random>
+#include <string>
+#include <vector>
+#include "engine.h"
+
+using std::string;
+using std::to_string;
+using std::vector;
+
+namespace {
+bool failed{false};
+
+void check(const bool ok, const string& what) {
+  if (ok) return;
+  std::cerr << what << "\n";
+  failed = true;
+}
+
+vector<pair64> random_md5s(std::mt19937_64& rng, const size_t count) {
+  vector<pair64> rv;
+  for (size_t idx = 0; idx < count; ++idx) rv.emplace_back(rng(), rng());
+  return rv;
+}
+
+template <std::size_t N>
+std::array<unsigned char, N> random_digest(std::mt19937_64& rng) {
+  std::array<unsigned char, N> rv;
+  for (auto& byte : rv) byte = static_cast<unsigned char>(rng());
+  return rv;
+}
+
+/** Every hash inserted is found again, at a rate tight enough that
+ * there are next to no false positives and at one loose enough that
+ * half of all lookups are.
+ */
+void check_no_false_negatives(std::mt19937_64& rng) {
+  for (const double fpr : {1e-6, 1e-2, 0.5}) {
+    const auto md5s = random_md5s(rng, 100000);
+    vector<sha1_digest> sha1s;
+    vector<sha256_digest> sha256s;
+    for (size_t idx = 0; idx < 10000; ++idx) {
+      sha1s.push_back(random_digest<20>(rng));
+      sha256s.push_back(random_digest<32>(rng));
+    }
+    BloomFilter filter(md5s.size() + sha1s.size() + sha256s.size(), fpr);
+    for (const auto& hash : md5s) filter.insert(bloom_key(hash));
+    for (const auto& hash : sha1s) filter.insert(bloom_key(hash));
+    for (const auto& hash : sha256s) filter.insert(bloom_key(hash));
+
+    uint64_t missed{0};
+    for (const auto& hash : md5s) missed += !filter.contains(bloom_key(hash));
+    for (const auto& hash : sha1s)
+      missed += !filter.contains(bloom_key(hash));
+    for (const auto& hash : sha256s)
+      missed += !filter.contains(bloom_key(hash));
+    check(0 == missed, to_string(missed) + " inserted hashes reported " +
+                           "absent at a false-positive rate of " +
+                           to_string(fpr));
+    check(filter.size() == md5s.size() + sha1s.size() + sha256s.size(),
+          "the filter miscounted what was inserted");
+  }
+}
+
+/** At 1e-6, a hundred thousand lookups of hashes never inserted should
+ * all but never hit.  The seed is fixed, so this can't flicker.
+ */
+void check_false_positive_rate(std::mt19937_64& rng) {
+  const auto md5s = random_md5s(rng, 100000);
+  BloomFilter filter(md5s.size(), 1e-6);
+  for (const auto& hash : md5s) filter.insert(bloom_key(hash));
+  uint64_t hits{0};
+  for (const auto& hash : random_md5s(rng, 100000))
+    hits += filter.contains(bloom_key(hash));
+  check(hits <= 5, to_string(hits) + " false positives in 100000 lookups " +
+                       "at a rate of 1e-6");
+}
+
+/** Lays hashes out as --bloom-verify does, with the filter in front of
+ * a sorted run like a mapped index, and a filter so loose that about
+ * half of all misses get past it.
+ */
+void check_verified_lookups(std::mt19937_64& rng) {
+  HashData data;
+  data.md5_store = random_md5s(rng, 10000);
+  std::sort(data.md5_store.begin(), data.md5_store.end());
+  data.md5s = HashView<pair64>(data.md5_store.data(),
+                               data.md5_store.data() + data.md5_store.size());
+  data.filter.reset(new BloomFilter(data.md5_store.size(), 0.5));
+  for (const auto& hash : data.md5_store) data.filter->insert(bloom_key(hash));
+
+  for (const auto& hash : data.md5_store)
+    check(contains_hash(data, from_pair64(hash)),
+          "a verified lookup missed " + from_pair64(hash));
+
+  uint64_t screened{0};
+  for (const auto& hash : random_md5s(rng, 10000)) {
+    screened += data.filter->contains(bloom_key(hash));
+    check(!contains_hash(data, from_pair64(hash)),
+          "a verified lookup hit " + from_pair64(hash) +
+              ", which was never loaded");
+  }
+  // Otherwise the index was never consulted.
+  check(0 < screened, "no false positive got past the filter to be checked");
+
+  // An index has no SHA-1s or SHA-256s, so none can be present, however
+  // the filter answers for them.
+  const auto sha1 = random_digest<20>(rng);
+  data.filter->insert(bloom_key(sha1));
+  string hex;
+  for (const auto byte : sha1) {
+    static const char digits[] = "0123456789ABCDEF";
+    hex += digits[byte >> 4];
+    hex += digits[byte & 15];
+  }
+  check(!contains_hash(data, hex),
+        "a verified lookup took the filter's word for a SHA-1");
+}
+}  // namespace
+
+int main() {
+  // Seeded, so that a failure can be reproduced.
+  std::mt19937_64 rng{20190401};
+  check_no_false_negatives(rng);
+  check_false_positive_rate(rng);
+  check_verified_lookups(rng);
+  return failed ? EXIT_FAILURE : EXIT_SUCCESS;
+}
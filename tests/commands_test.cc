@@ -0,0 +1,76 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+// Checks that a command the server can't make sense of gets a NOT OK
+// reply, and that the session carries on afterwards, so that a client
+// can always tell a protocol error from a dropped connection.
+
+#include <cstdlib>
+#include <iostream>
+#include <string>
+#include "server_fixture.h"
+
+using std::string;
+
+namespace {
+bool failed{false};
+
+void check(const bool ok, const string& what) {
+  if (ok) return;
+  std::cerr << what << "\n";
+  failed = true;
+}
+
+const string present{"0123456789ABCDEF0123456789ABCDEF"};
+
+/** Checks that a session sending line between VERSION: and a QUERY
+ * gets reply for it, and still has the QUERY answered.
+ */
+void check_reply(const string& line, const string& reply) {
+  const auto written = run_session("VERSION: 2.0\r\n" + line +
+                                   "\r\nQUERY " + present + "\r\nBYE\r\n");
+  check("OK\r\n" + reply + "OK 1\r\n" == written,
+        "\"" + line + "\" was answered \"" + written + "\"");
+}
+
+void check_gibberish() {
+  for (const string& line :
+       {string("FROBNICATE"), string("hello, world"), "QUERIES " + present,
+        string("BYEBYE"), string("\x01\x7f\xff garbage"), string(300, 'x')})
+    check_reply(line, "NOT OK unknown command\r\n");
+
+  // Nor does a client have to have said VERSION: first.
+  const auto written = run_session("FROBNICATE\r\nBYE\r\n");
+  check("NOT OK unknown command\r\n" == written,
+        "gibberish before VERSION: was answered \"" + written + "\"");
+}
+
+void check_missing_arguments() {
+  for (const auto& verb :
+       {"VERSION:", "QUERYPREFIX", "DATASET", "QUERYSOURCE", "FORMAT:"}) {
+    check_reply(verb, "NOT OK missing argument\r\n");
+    // Trailing spaces don't count as an argument.
+    check_reply(verb + string("   "), "NOT OK missing argument\r\n");
+  }
+}
+}  // namespace
+
+int main() {
+  fixture::serve({present});
+  check_gibberish();
+  check_missing_arguments();
+  return failed ? EXIT_FAILURE : EXIT_SUCCESS;
+}
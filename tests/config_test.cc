@@ -0,0 +1,140 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+// Checks that settings written to a --config file come back out of it
+// as they went in, and that the command line overrides them.
+
+#include <cstdio>
+#include <cstdlib>
+#include <fstream>
+#include <iostream>
+#include <stdexcept>
+#include <string>
+#include <vector>
+#include "main.h"
+
+using boost::program_options::variables_map;
+using std::string;
+using std::vector;
+
+namespace {
+bool failed{false};
+
+void check(const bool ok, const string& what) {
+  if (ok) return;
+  std::cerr << what << "\n";
+  failed = true;
+}
+
+const char config_path[] = "config_test.conf";
+
+void write_config(const string& text) {
+  std::ofstream config{config_path};
+  config << text;
+}
+
+/** Reads options as nsrlsvr does from the arguments after its name. */
+variables_map read(vector<const char*> args) {
+  args.insert(args.begin(), "nsrlsvr");
+  variables_map vm;
+  read_options(static_cast<int>(args.size()), args.data(), vm);
+  return vm;
+}
+
+/** Whether reading args fails with a message that names the file. */
+bool is_refused(const vector<const char*>& args) {
+  try {
+    read(args);
+  } catch (std::runtime_error& e) {
+    return string::npos != string(e.what()).find(config_path);
+  }
+  return false;
+}
+
+void check_defaults() {
+  const auto vm = read({});
+  check(9120 == vm["port"].as<uint16_t>(), "the default port isn't 9120");
+  check(vm["port"].defaulted(), "the port wasn't left at its default");
+  check("::" == vm["bind-address"].as<string>(),
+        "the default bind address isn't ::");
+  check(256 == vm["max-connections"].as<unsigned int>(),
+        "the default connection limit isn't 256");
+  check("info" == vm["log-level"].as<string>(),
+        "the default log level isn't info");
+}
+
+void check_round_trip() {
+  write_config(
+      "# nsrlsvr settings\n"
+      "port = 9200\n"
+      "file = first.txt\n"
+      "file = second.txt\n"
+      "bind-address = 127.0.0.1\n"
+      "max-connections = 12\n"
+      "log-level = debug\n");
+  const auto vm = read({"--config", config_path});
+  check(9200 == vm["port"].as<uint16_t>(), "port didn't come back as 9200");
+  check(!vm["port"].defaulted(), "port was taken as its default");
+  check((vector<string>{"first.txt", "second.txt"}) ==
+            vm["file"].as<vector<string>>(),
+        "file didn't come back as both files, in order");
+  check("127.0.0.1" == vm["bind-address"].as<string>(),
+        "bind-address didn't come back as 127.0.0.1");
+  check(12 == vm["max-connections"].as<unsigned int>(),
+        "max-connections didn't come back as 12");
+  check("debug" == vm["log-level"].as<string>(),
+        "log-level didn't come back as debug");
+}
+
+void check_command_line_wins() {
+  write_config(
+      "port = 9200\n"
+      "file = first.txt\n"
+      "max-connections = 12\n");
+  const auto vm =
+      read({"--port", "9300", "--config", config_path, "--file", "third.txt"});
+  check(9300 == vm["port"].as<uint16_t>(),
+        "the config file's port overrode --port");
+  check((vector<string>{"third.txt"}) == vm["file"].as<vector<string>>(),
+        "the config file's files were added to --file's");
+  check(12 == vm["max-connections"].as<unsigned int>(),
+        "a setting only the config file gave was lost");
+}
+
+void check_bad_files() {
+  write_config("frobnicate = 1\n");
+  check(is_refused({"--config", config_path}),
+        "an unknown setting was accepted");
+  write_config("port = ninety-one twenty\n");
+  check(is_refused({"--config", config_path}), "a bad port was accepted");
+  // Only makes sense on the command line.
+  write_config("check = true\n");
+  check(is_refused({"--config", config_path}),
+        "a command-line option was accepted from the file");
+  std::remove(config_path);
+  check(is_refused({"--config", config_path}),
+        "a missing config file was accepted");
+}
+}  // namespace
+
+int main() {
+  check_defaults();
+  check_round_trip();
+  check_command_line_wins();
+  check_bad_files();
+  std::remove(config_path);
+  return failed ? EXIT_FAILURE : EXIT_SUCCESS;
+}
@@ -0,0 +1,132 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+// Probes the metrics port's /health before, during and after a load of
+// a hash file, and after a reload fails, as an orchestrator would.
+
+#include <cstdio>
+#include <cstdlib>
+#include <fstream>
+#include <iostream>
+#include <memory>
+#include <random>
+#include <string>
+#include <vector>
+#include "server_fixture.h"
+
+using std::string;
+using std::to_string;
+using std::vector;
+
+namespace {
+bool failed{false};
+
+void check(const bool ok, const string& what) {
+  if (ok) return;
+  std::cerr << what << "\n";
+  failed = true;
+}
+
+const char hash_file[] = "health_test.txt";
+
+/** What the metrics port sends back for a GET of path. */
+string fetch(const string& path) {
+  StringSocketBuffer buffer("GET " + path + " HTTP/1.0\r\n\r\n", 4096);
+  std::iostream stream(&buffer);
+  answer_http(stream, answer_scrape);
+  return buffer.written();
+}
+
+/** Checks that a probe gets status, and says so in its body. */
+void check_health(const string& when, const string& status,
+                  const string& body) {
+  const auto response = fetch("/health");
+  check(0 == response.find("HTTP/1.0 " + status + "\r\n"),
+        when + ", /health wasn't answered " + status);
+  const auto rest = response.find("\r\n\r\n");
+  check(string::npos != rest && body + "\n" == response.substr(rest + 4),
+        when + ", /health didn't say \"" + body + "\"");
+}
+
+/** What probes made partway through the load turned up. */
+vector<string> mid_load;
+
+/** Where the load reports its progress: probes /health each time. */
+void probe_mid_load(const LogLevel, const string&) {
+  mid_load.push_back(fetch("/health"));
+}
+
+vector<string> write_hash_file(std::mt19937_64& rng) {
+  const char digits[] = "0123456789ABCDEF";
+  vector<string> rv;
+  std::ofstream out(hash_file);
+  for (size_t idx = 0; idx < 5000; ++idx) {
+    string hash;
+    for (size_t digit = 0; digit < 32; ++digit) hash += digits[rng() % 16];
+    out << hash << "\n";
+    rv.push_back(hash);
+  }
+  return rv;
+}
+}  // namespace
+
+int main() {
+  // Seeded, so that a failure can be reproduced.
+  std::mt19937_64 rng{20190401};
+  const auto written = write_hash_file(rng);
+
+  // nsrlsvr starts out loading, with no hashes, and its metrics port is
+  // already up.
+  fixture::state = ServerState::Loading;
+  check_health("before the load", "503 Service Unavailable", "loading");
+
+  // Loaded as main.cc does it, with progress reported every thousand
+  // hashes, which is when the probes partway through are made.
+  HashSets sets;
+  LoadOptions options;
+  options.progress_interval = 1000;
+  options.log = probe_mid_load;
+  try {
+    load_hashes(sets, {hash_file}, options);
+  } catch (LoadError& e) {
+    check(false, string("couldn't load the hashes: ") + e.what());
+  }
+  std::remove(hash_file);
+  std::unique_ptr<BloomFilter> filter;
+  std::unique_ptr<CompactSet> compact;
+  fixture::hashes = make_hash_data(sets, filter, compact);
+  check(!mid_load.empty(), "no probe was made partway through the load");
+  for (const auto& response : mid_load)
+    check(0 == response.find("HTTP/1.0 503 Service Unavailable\r\n"),
+          "partway through the load, /health wasn't answered 503");
+
+  check(written.size() == fixture::hashes->size(),
+        "the load got " + to_string(fixture::hashes->size()) +
+            " hashes, not " + to_string(written.size()));
+  fixture::state = ServerState::Ready;
+  check_health("once loaded", "200 OK", "ready");
+  check("OK\r\nOK 1\r\n" ==
+            run_session("VERSION: 2.0\r\nQUERY " + written[0] + "\r\n"),
+        "once loaded, a hash in the file wasn't found");
+
+  // A failed reload leaves the old hashes served, so the server is
+  // still fit for traffic; a good one makes it ready again.
+  fixture::state = ServerState::Degraded;
+  check_health("after a failed reload", "200 OK", "degraded");
+  fixture::state = ServerState::Ready;
+  check_health("after a good reload", "200 OK", "ready");
+  return failed ? EXIT_FAILURE : EXIT_SUCCESS;
+}
@@ -0,0 +1,106 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+// Checks that clients ending their lines with CRLF, with a bare LF, or
+// with a mix of the two are all understood alike, however the lines
+// are split across reads.
+
+#include <cstdlib>
+#include <iostream>
+#include <string>
+#include <vector>
+#include "server_fixture.h"
+
+using std::string;
+using std::to_string;
+
+namespace {
+bool failed{false};
+
+void check(const bool ok, const string& what) {
+  if (ok) return;
+  std::cerr << what << "\n";
+  failed = true;
+}
+
+const string present{"0123456789ABCDEF0123456789ABCDEF"};
+const string absent{"FEDCBA9876543210FEDCBA9876543210"};
+
+/** Shows the line endings in text, for a failure to be readable. */
+string visible(const string& text) {
+  string rv;
+  for (const auto ch : text) {
+    if ('\r' == ch)
+      rv += "\\r";
+    else if ('\n' == ch)
+      rv += "\\n";
+    else
+      rv += ch;
+  }
+  return rv;
+}
+
+/** Checks that input gets reply, whether it arrives in one read, a
+ * byte at a time, or so that CR and LF fall in different reads.
+ */
+void check_session(const string& what, const string& input,
+                   const string& reply) {
+  for (const size_t chunk : {4096, 1, 2, 3, 13}) {
+    const auto written = run_session(input, chunk);
+    check(reply == written, what + ", read " + to_string(chunk) +
+                                " bytes at a time, was answered \"" +
+                                visible(written) + "\"");
+  }
+}
+
+void check_endings() {
+  const string query = "QUERY " + present + " " + absent;
+  const string answered = "OK\r\nOK 10\r\n";
+  check_session("CRLF", "VERSION: 2.0\r\n" + query + "\r\nBYE\r\n",
+                answered);
+  check_session("LF", "VERSION: 2.0\n" + query + "\nBYE\n", answered);
+  check_session("CRLF then LF", "VERSION: 2.0\r\n" + query + "\nBYE\r\n",
+                answered);
+  check_session("LF then CRLF", "VERSION: 2.0\n" + query + "\r\nBYE\n",
+                answered);
+  // Trailing blanks are trimmed along with the CR.
+  check_session("trailing blanks", "VERSION: 2.0 \t\r\n" + query +
+                                       "  \r\nBYE\t\n",
+                answered);
+  // A client that hangs up after its last line, without a newline or
+  // BYE, still has that line answered.
+  check_session("no final newline", "VERSION: 2.0\r\n" + query, answered);
+  check_session("protocol 1 over LF", "VERSION: 1.0\n" + query + "\nBYE\n",
+                answered);
+}
+
+/** A blank line ends the session as BYE would, whichever its ending. */
+void check_blank_lines() {
+  const string after = "QUERY " + present + "\r\n";
+  check_session("a blank CRLF line", "VERSION: 2.0\r\n\r\n" + after,
+                "OK\r\n");
+  check_session("a blank LF line", "VERSION: 2.0\n\n" + after, "OK\r\n");
+  check_session("a line of blanks", "VERSION: 2.0\r\n \t\r\n" + after,
+                "OK\r\n");
+}
+}  // namespace
+
+int main() {
+  fixture::serve({present});
+  check_endings();
+  check_blank_lines();
+  return failed ? EXIT_FAILURE : EXIT_SUCCESS;
+}
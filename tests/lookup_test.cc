@@ -0,0 +1,270 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+// Checks each way nsrlsvr can hold hashes against std::binary_search
+// over the same sorted vector: the plain array, a mapped index,
+// CompactSet, ShardTable, and a Bloom filter, which may only err on the
+// side of a hit.  The edge cases come first, then thousands of seeded
+// random sets.
+
+#include <fcntl.h>
+#include <sys/mman.h>
+#include <unistd.h>
+#include <algorithm>
+#include <cstdio>
+#include <cstdlib>
+#include <iostream>
+#include <limits>
+#include <memory>
+#include <random>
+#include <string>
+#include <vector>
+#include "engine.h"
+
+using std::string;
+using std::unique_ptr;
+using std::vector;
+
+namespace {
+bool failed{false};
+unsigned long long mismatches{0};
+
+void check(const bool ok, const string& what) {
+  if (ok) return;
+  // A broken store tends to get thousands wrong; the first few say
+  // as much as all of them.
+  if (++mismatches <= 20) std::cerr << what << "\n";
+  failed = true;
+}
+
+const auto top = std::numeric_limits<unsigned long long>::max();
+
+vector<pair64> sorted_set(vector<pair64> hashes) {
+  std::sort(hashes.begin(), hashes.end());
+  hashes.erase(std::unique(hashes.begin(), hashes.end()), hashes.end());
+  return hashes;
+}
+
+vector<pair64> random_set(std::mt19937_64& rng, const size_t count) {
+  vector<pair64> rv;
+  for (size_t idx = 0; idx < count; ++idx) rv.emplace_back(rng(), rng());
+  return sorted_set(rv);
+}
+
+/** What to look up in sorted: every member, each one's neighbours on
+ * either side in both halves, the extremes, and some random hashes.
+ */
+vector<pair64> probes_for(const vector<pair64>& sorted,
+                          std::mt19937_64& rng) {
+  vector<pair64> rv{{0, 0}, {0, top}, {top, 0}, {top, top}};
+  for (const auto& hash : sorted) {
+    rv.push_back(hash);
+    rv.emplace_back(hash.first, hash.second + 1);
+    rv.emplace_back(hash.first, hash.second - 1);
+    rv.emplace_back(hash.first + 1, hash.second);
+    rv.emplace_back(hash.first - 1, hash.second);
+  }
+  for (size_t idx = 0; idx < 100 + sorted.size(); ++idx)
+    rv.emplace_back(rng(), rng());
+  return rv;
+}
+
+/** HashData serving a copy of sorted from the heap, as a load does. */
+std::shared_ptr<HashData> heap_data(const vector<pair64>& sorted) {
+  auto rv = std::make_shared<HashData>();
+  rv->md5_store = sorted;
+  rv->md5s = HashView<pair64>(rv->md5_store.data(),
+                              rv->md5_store.data() + rv->md5_store.size());
+  return rv;
+}
+
+/** HashData serving sorted out of a mapped file laid out as an --index
+ * is, a 24-byte header and then the records.
+ */
+std::shared_ptr<HashData> mapped_data(const vector<pair64>& sorted) {
+  const char path[] = "lookup_test.idx";
+  const size_t header{24};
+  const size_t length = header + sorted.size() * sizeof(pair64);
+  const int fd = open(path, O_RDWR | O_CREAT | O_TRUNC, 0600);
+  const string zeros(header, '\0');
+  const bool written =
+      0 <= fd &&
+      static_cast<ssize_t>(header) == write(fd, zeros.data(), header) &&
+      static_cast<ssize_t>(length - header) ==
+          write(fd, sorted.data(), length - header);
+  void* base =
+      written ? mmap(nullptr, length, PROT_READ, MAP_SHARED, fd, 0) : nullptr;
+  if (0 <= fd) close(fd);
+  std::remove(path);
+  if (!written || MAP_FAILED == base) {
+    check(false, "couldn't map a test index");
+    return heap_data(sorted);
+  }
+
+  auto rv = std::make_shared<HashData>();
+  rv->mapping = base;
+  rv->mapping_length = length;
+  const auto records = reinterpret_cast<const pair64*>(
+      static_cast<const char*>(base) + header);
+  rv->md5s = HashView<pair64>(records, records + sorted.size());
+  return rv;
+}
+
+/** Looks up every probe in every way of holding sorted, and checks that
+ * each agrees with std::binary_search.
+ */
+void check_md5s(const string& name, const vector<pair64>& sorted,
+                std::mt19937_64& rng) {
+  const HashView<pair64> view(sorted.data(), sorted.data() + sorted.size());
+  const CompactSet compact(sorted);
+  vector<unique_ptr<ShardTable>> shards;
+  for (const unsigned bits : {1, 4, 8, 16})
+    shards.emplace_back(new ShardTable(view, bits));
+  BloomFilter filter(std::max<size_t>(sorted.size(), 1), 1e-3);
+  for (const auto& hash : sorted) filter.insert(bloom_key(hash));
+
+  // And the same again as contains_hash() reaches them.
+  const auto plain = heap_data(sorted);
+  const auto mapped = mapped_data(sorted);
+  const auto sharded = heap_data(sorted);
+  sharded->md5_shards.reset(new ShardTable(sharded->md5s, 8));
+  HashData compacted;
+  compacted.compact_md5s.reset(new CompactSet(sorted));
+  // As --bloom-verify has it, with a filter that lets most misses by.
+  const auto verified = mapped_data(sorted);
+  verified->filter.reset(
+      new BloomFilter(std::max<size_t>(sorted.size(), 1), 0.5));
+  for (const auto& hash : sorted) verified->filter->insert(bloom_key(hash));
+  HashData filtered;
+  filtered.filter.reset(
+      new BloomFilter(std::max<size_t>(sorted.size(), 1), 1e-3));
+  for (const auto& hash : sorted) filtered.filter->insert(bloom_key(hash));
+
+  for (const auto& probe : probes_for(sorted, rng)) {
+    const bool expected =
+        std::binary_search(sorted.cbegin(), sorted.cend(), probe);
+    const auto hex = from_pair64(probe);
+    const auto agrees = [&](const bool right, const char* store) {
+      if (!right)
+        check(false, name + ": " + store + " says " + hex + " is " +
+                         (expected ? "absent" : "present"));
+    };
+    agrees(expected == compact.contains(probe), "CompactSet");
+    for (const auto& table : shards)
+      agrees(expected == table->contains(view, probe), "ShardTable");
+    agrees(!expected || filter.contains(bloom_key(probe)), "BloomFilter");
+    agrees(expected == contains_hash(*plain, hex), "the hash array");
+    agrees(expected == contains_hash(*mapped, hex), "a mapped index");
+    agrees(expected == contains_hash(*sharded, hex), "--shard-bits");
+    agrees(expected == contains_hash(compacted, hex), "--compact");
+    agrees(expected == contains_hash(*verified, hex), "--bloom-verify");
+    agrees(!expected || contains_hash(filtered, hex), "--bloom");
+  }
+}
+
+template <std::size_t N>
+std::array<unsigned char, N> random_digest(std::mt19937_64& rng) {
+  std::array<unsigned char, N> rv;
+  for (auto& byte : rv) byte = static_cast<unsigned char>(rng());
+  return rv;
+}
+
+template <std::size_t N>
+string to_hex(const std::array<unsigned char, N>& digest) {
+  static const char digits[] = "0123456789ABCDEF";
+  string rv;
+  for (const auto byte : digest) {
+    rv += digits[byte >> 4];
+    rv += digits[byte & 15];
+  }
+  return rv;
+}
+
+/** As check_md5s(), for SHA-1s or SHA-256s, which are only ever held
+ * in a sorted vector.
+ */
+template <std::size_t N>
+void check_shas(const string& name, const size_t count,
+                vector<std::array<unsigned char, N>> HashData::*member,
+                std::mt19937_64& rng) {
+  vector<std::array<unsigned char, N>> sorted;
+  for (size_t idx = 0; idx < count; ++idx)
+    sorted.push_back(random_digest<N>(rng));
+  std::sort(sorted.begin(), sorted.end());
+  HashData data;
+  data.*member = sorted;
+
+  auto probes = sorted;
+  for (const auto& digest : sorted) {
+    auto next = digest;
+    ++next[N - 1];
+    probes.push_back(next);
+  }
+  for (size_t idx = 0; idx < 100; ++idx)
+    probes.push_back(random_digest<N>(rng));
+  for (const auto& probe : probes) {
+    const bool expected =
+        std::binary_search(sorted.cbegin(), sorted.cend(), probe);
+    if (expected != contains_hash(data, to_hex(probe)))
+      check(false, name + ": contains_hash() says " + to_hex(probe) +
+                       " is " + (expected ? "absent" : "present"));
+  }
+}
+
+void check_edge_cases(std::mt19937_64& rng) {
+  const auto some = rng();
+  check_md5s("no hashes", {}, rng);
+  check_md5s("one hash", random_set(rng, 1), rng);
+  check_md5s("one hash, the lowest", {{0, 0}}, rng);
+  check_md5s("one hash, the highest", {{top, top}}, rng);
+  check_md5s("two hashes", random_set(rng, 2), rng);
+  check_md5s("two hashes, the extremes", {{0, 0}, {top, top}}, rng);
+  check_md5s("two hashes with the same first half",
+             {{some, 1}, {some, 2}}, rng);
+  check_md5s("two hashes either side of a first half",
+             sorted_set({{some, top}, {some + 1, 0}}), rng);
+  check_md5s("three hashes", random_set(rng, 3), rng);
+
+  // Hashes bunched up in one bucket or shard, and a run of them that
+  // only differ in their second half.
+  vector<pair64> bunched;
+  for (size_t idx = 0; idx < 2000; ++idx)
+    bunched.emplace_back(0x5555000000000000ULL | (rng() >> 16), rng());
+  for (size_t idx = 0; idx < 100; ++idx) bunched.emplace_back(some, rng());
+  check_md5s("bunched hashes", sorted_set(bunched), rng);
+}
+}  // namespace
+
+int main() {
+  // Seeded, so that a failure can be reproduced.
+  std::mt19937_64 rng{20190401};
+  check_edge_cases(rng);
+  for (size_t round = 0; round < 100; ++round) {
+    const auto count = static_cast<size_t>(rng() % 2000);
+    check_md5s(std::to_string(count) + " random hashes",
+               random_set(rng, count), rng);
+  }
+  // Enough for CompactSet to use ten bucket bits.
+  check_md5s("20000 random hashes", random_set(rng, 20000), rng);
+
+  for (const size_t count : {0, 1, 2, 1000}) {
+    check_shas(std::to_string(count) + " SHA-1s", count, &HashData::sha1s,
+               rng);
+    check_shas(std::to_string(count) + " SHA-256s", count,
+               &HashData::sha256s, rng);
+  }
+  return failed ? EXIT_FAILURE : EXIT_SUCCESS;
+}
@@ -0,0 +1,133 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+// Scrapes the metrics port's /metrics as Prometheus would, and checks
+// that it names every metric and that a query moves the counters.
+
+#include <chrono>
+#include <cstdlib>
+#include <iostream>
+#include <sstream>
+#include <string>
+#include <thread>
+#include "server_fixture.h"
+
+using std::string;
+using std::to_string;
+
+namespace {
+bool failed{false};
+
+void check(const bool ok, const string& what) {
+  if (ok) return;
+  std::cerr << what << "\n";
+  failed = true;
+}
+
+const string present{"0123456789ABCDEF0123456789ABCDEF"};
+const string absent{"FEDCBA9876543210FEDCBA9876543210"};
+
+/** What the metrics port sends back for a request. */
+string fetch(const string& method, const string& path) {
+  StringSocketBuffer buffer(method + " " + path + " HTTP/1.0\r\n\r\n", 4096);
+  std::iostream stream(&buffer);
+  answer_http(stream, answer_scrape);
+  return buffer.written();
+}
+
+/** A metric's value in a scrape, or -1 if it's not there. */
+double value_of(const string& scrape, const string& name) {
+  std::istringstream lines{scrape};
+  string line;
+  while (std::getline(lines, line))
+    if (0 == line.compare(0, name.size() + 1, name + " "))
+      return std::stod(line.substr(name.size() + 1));
+  return -1;
+}
+
+void check_names() {
+  const auto response = fetch("GET", "/metrics");
+  check(0 == response.find("HTTP/1.0 200 OK\r\n"),
+        "a scrape wasn't answered 200 OK");
+  check(string::npos !=
+            response.find("Content-Type: text/plain; version=0.0.4\r\n"),
+        "a scrape wasn't in Prometheus' text format");
+  for (const auto& metric :
+       {"nsrlsvr_connections_total counter", "nsrlsvr_queries_total counter",
+        "nsrlsvr_hashes_queried_total counter", "nsrlsvr_hits_total counter",
+        "nsrlsvr_misses_total counter",
+        "nsrlsvr_query_duration_seconds histogram",
+        "nsrlsvr_hash_duration_seconds histogram",
+        "nsrlsvr_active_connections gauge", "nsrlsvr_loaded_hashes gauge",
+        "nsrlsvr_uptime_seconds gauge"})
+    check(string::npos != response.find(string("\n# TYPE ") + metric + "\n"),
+          string("a scrape has no ") + metric);
+}
+
+void check_query_counted() {
+  fixture::serve({present, "00000000000000000000000000000001"});
+  const auto before = fetch("GET", "/metrics");
+  check(2 == value_of(before, "nsrlsvr_loaded_hashes"),
+        "the loaded hashes weren't counted");
+
+  const auto reply = run_session("VERSION: 2.0\r\nQUERY " + present + " " +
+                                 absent + "\r\nBYE\r\n");
+  check("OK\r\nOK 10\r\n" == reply, "the query was answered " + reply);
+
+  const auto after = fetch("GET", "/metrics");
+  for (const auto& change :
+       {std::make_pair("nsrlsvr_queries_total", 1),
+        std::make_pair("nsrlsvr_hashes_queried_total", 2),
+        std::make_pair("nsrlsvr_hits_total", 1),
+        std::make_pair("nsrlsvr_misses_total", 1),
+        std::make_pair("nsrlsvr_query_duration_seconds_count", 1)}) {
+    const auto moved =
+        value_of(after, change.first) - value_of(before, change.first);
+    check(change.second == moved, string(change.first) + " went up by " +
+                                      to_string(moved) + ", not " +
+                                      to_string(change.second));
+  }
+}
+
+/** The uptime counts from when nsrlsvr started, as STATUS's does, even
+ * while the hashes are loading.
+ */
+void check_uptime() {
+  std::this_thread::sleep_for(std::chrono::milliseconds(1100));
+  fixture::state = ServerState::Loading;
+  const auto scrape = fetch("GET", "/metrics");
+  fixture::state = ServerState::Ready;
+  check(1 <= value_of(scrape, "nsrlsvr_uptime_seconds"),
+        "the uptime didn't count the time spent loading");
+}
+
+void check_refusals() {
+  check(0 == fetch("GET", "/nowhere").find("HTTP/1.0 404 "),
+        "an unknown path wasn't answered 404");
+  check(0 == fetch("POST", "/metrics").find("HTTP/1.0 405 "),
+        "a POST to /metrics wasn't answered 405");
+  check(0 == fetch("", "").find("HTTP/1.0 400 "),
+        "a request with no request line wasn't answered 400");
+}
+}  // namespace
+
+int main() {
+  check_names();
+  check_query_counted();
+  check_uptime();
+  check_refusals();
+  return failed ? EXIT_FAILURE : EXIT_SUCCESS;
+}
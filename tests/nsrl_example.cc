@@ -0,0 +1,39 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+// The example in nsrl.h's description of HashStore, run to check that
+// it still does what its comments say.  It includes only nsrl.h and
+// links only the nsrl library, as a program embedding it would.
+
+#include <cstdlib>
+#include <iostream>
+#include <vector>
+#include "nsrl.h"
+
+int main() {
+  const auto store = HashStore::from_hashes(
+      {"8A8B5E9F4D64E4E8C4C1B3AA493F8E5E",
+       "da39a3ee5e6b4b0d3255bfef95601890afd80709"});
+  const bool found = store.contains("8a8b5e9f4d64e4e8c4c1b3aa493f8e5e");
+  const auto results =
+      store.query({"DA39A3EE5E6B4B0D3255BFEF95601890AFD80709",
+                   "00000000000000000000000000000000", "not a hash"});
+  if (!found || results != std::vector<bool>{true, false, false}) {
+    std::cerr << "HashStore doesn't do what nsrl.h's example says\n";
+    return EXIT_FAILURE;
+  }
+  return EXIT_SUCCESS;
+}
@@ -0,0 +1,118 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+// Sends VERSION, three QUERYs and BYE as one write, the way nsrllookup
+// pipelines them, and checks that they're answered in order and the
+// session ends at BYE, whether the server gets them in one read, a
+// byte at a time, or seven bytes at a time.
+
+#include <algorithm>
+#include <cstdlib>
+#include <iostream>
+#include <string>
+#include <vector>
+#include "server_fixture.h"
+
+using std::string;
+using std::to_string;
+using std::vector;
+
+namespace {
+bool failed{false};
+
+void check(const bool ok, const string& what) {
+  if (ok) return;
+  std::cerr << what << "\n";
+  failed = true;
+}
+
+const string first{"0123456789ABCDEF0123456789ABCDEF"};
+const string second{"00112233445566778899AABBCCDDEEFF"};
+const string absent{"FEDCBA9876543210FEDCBA9876543210"};
+
+/** A command the client pipelines, and the reply it should get. */
+struct Exchange {
+  string command;
+  string reply;
+};
+
+const vector<Exchange> exchanges{
+    {"VERSION: 2.0", "OK\r\n"},
+    {"QUERY " + first + " " + absent, "OK 10\r\n"},
+    {"QUERY " + absent, "OK 0\r\n"},
+    {"QUERY " + absent + " " + second + " " + first, "OK 011\r\n"},
+    {"BYE", ""}};
+
+void check_pipelined(const size_t chunk) {
+  const auto how = "read " + to_string(chunk) + " bytes at a time";
+  string input;
+  // Where each command's line ends in the input.
+  vector<size_t> ends;
+  string replies;
+  for (const auto& exchange : exchanges) {
+    input += exchange.command + "\r\n";
+    ends.push_back(input.size());
+    replies += exchange.reply;
+  }
+  // Anything after BYE mustn't be answered.
+  const auto bye_end = input.size();
+  input += "QUERY " + first + "\r\n";
+
+  fixture::logged.clear();
+  StringSocketBuffer buffer(input, chunk);
+  std::iostream stream(&buffer);
+  handle_client(stream, "client");
+  check(replies == buffer.written(),
+        how + ", the replies were \"" + buffer.written() + "\"");
+
+  // Each reply has to go out before the server waits on the client for
+  // more, since a client may not send more until it has it: it must be
+  // sent before anything past the read its command ended in.
+  const auto& writes = buffer.writes();
+  size_t sent{0};
+  size_t write{0};
+  size_t replied{0};
+  for (size_t idx = 0; idx < exchanges.size(); ++idx) {
+    replied += exchanges[idx].reply.size();
+    while (sent < replied && write < writes.size())
+      sent += writes[write++].second.size();
+    if (sent < replied) break;
+    const auto by = std::min(input.size(), (ends[idx] + chunk - 1) / chunk *
+                                               chunk);
+    if (0 < write && writes[write - 1].first > by)
+      check(false, how + ", the reply to " + exchanges[idx].command +
+                       " wasn't sent until byte " +
+                       to_string(writes[write - 1].first) + " was read");
+  }
+
+  // The session ends at BYE: nothing much past it is read, and the
+  // session closes cleanly having counted each hash queried.
+  const auto bye_read = (bye_end + chunk - 1) / chunk * chunk;
+  check(buffer.read() <= std::min(input.size(), bye_read),
+        how + ", the input was read up to byte " +
+            to_string(buffer.read()) + ", well past BYE");
+  check(std::find(fixture::logged.cbegin(), fixture::logged.cend(),
+                  "client closed session after 6 queries") !=
+            fixture::logged.cend(),
+        how + ", the session didn't close after 6 queries");
+}
+}  // namespace
+
+int main() {
+  fixture::serve({first, second});
+  for (const size_t chunk : {4096, 1, 7}) check_pipelined(chunk);
+  return failed ? EXIT_FAILURE : EXIT_SUCCESS;
+}
@@ -0,0 +1,245 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+// Checks QUERYPREFIX and NEAREST against a scan of the same hashes in
+// a sorted vector, over seeded random hashes bunched so that prefixes
+// and near misses actually turn up.
+
+#include <algorithm>
+#include <cstdlib>
+#include <iostream>
+#include <random>
+#include <sstream>
+#include <string>
+#include <vector>
+#include "server_fixture.h"
+
+using std::string;
+using std::to_string;
+using std::vector;
+
+namespace {
+bool failed{false};
+
+void check(const bool ok, const string& what) {
+  if (ok) return;
+  std::cerr << what << "\n";
+  failed = true;
+}
+
+const char digits[] = "0123456789ABCDEF";
+
+string random_hex(std::mt19937_64& rng, const size_t length) {
+  string rv;
+  for (size_t idx = 0; idx < length; ++idx) rv += digits[rng() % 16];
+  return rv;
+}
+
+/** hash with count of its digits, picked at random, changed. */
+string changed(std::mt19937_64& rng, string hash, const size_t count) {
+  vector<size_t> places(hash.size());
+  for (size_t idx = 0; idx < places.size(); ++idx) places[idx] = idx;
+  std::shuffle(places.begin(), places.end(), rng);
+  for (size_t idx = 0; idx < count; ++idx) {
+    auto& digit = hash[places[idx]];
+    digit = digits[(string(digits).find(digit) + 1 + rng() % 15) % 16];
+  }
+  return hash;
+}
+
+/** The hashes served, each type sorted, MD5s, then SHA-1s, then
+ * SHA-256s, which is the order the server lists its matches in.
+ */
+vector<vector<string>> served;
+
+/** Serves families of hashes that share a prefix or differ in a digit
+ * or two, among plenty of unrelated ones.
+ */
+void serve_hashes(std::mt19937_64& rng) {
+  served.assign(3, {});
+  const size_t lengths[] = {32, 40, 64};
+  for (size_t type = 0; type < 3; ++type) {
+    auto& hashes = served[type];
+    const auto length = lengths[type];
+    for (size_t idx = 0; idx < 2000; ++idx)
+      hashes.push_back(random_hex(rng, length));
+    for (size_t family = 0; family < 40; ++family) {
+      const auto base = random_hex(rng, length);
+      const auto sharing = 1 + rng() % 120;
+      for (size_t idx = 0; idx < sharing; ++idx) {
+        const auto shared = 8 + rng() % 4;
+        hashes.push_back(base.substr(0, shared) +
+                         random_hex(rng, length - shared));
+      }
+      const auto near = rng() % 8;
+      for (size_t idx = 0; idx < near; ++idx)
+        hashes.push_back(changed(rng, base, rng() % 4));
+    }
+    std::sort(hashes.begin(), hashes.end());
+    hashes.erase(std::unique(hashes.begin(), hashes.end()), hashes.end());
+  }
+  vector<string> all;
+  for (const auto& hashes : served)
+    all.insert(all.end(), hashes.cbegin(), hashes.cend());
+  fixture::serve(all);
+}
+
+string answer(const uint64_t count, const vector<string>& listed) {
+  string rv = "OK " + to_string(count);
+  for (const auto& hash : listed) rv += " " + hash;
+  return rv + "\r\n";
+}
+
+/** What QUERYPREFIX prefix limit should say. */
+string expected_prefix(const string& prefix, const size_t limit) {
+  string upper;
+  for (const auto ch : prefix) upper += static_cast<char>(toupper(ch));
+  uint64_t count{0};
+  vector<string> listed;
+  for (const auto& hashes : served)
+    for (const auto& hash : hashes) {
+      if (0 != hash.compare(0, upper.size(), upper)) continue;
+      ++count;
+      if (listed.size() < limit) listed.push_back(hash);
+    }
+  return answer(count, listed);
+}
+
+/** What NEAREST hash distance should say. */
+string expected_nearest(const string& hash, const unsigned distance) {
+  uint64_t count{0};
+  vector<string> listed;
+  for (const auto& hashes : served)
+    for (const auto& other : hashes) {
+      if (other.size() != hash.size()) continue;
+      unsigned differing{0};
+      for (size_t idx = 0; idx < hash.size(); ++idx)
+        differing += other[idx] != hash[idx];
+      if (differing > distance) continue;
+      ++count;
+      if (listed.size() < 100) listed.push_back(other);
+    }
+  return answer(count, listed);
+}
+
+/** Sends every command in one session and checks each reply against
+ * the one expected.
+ */
+void check_replies(const vector<string>& commands,
+                   const vector<string>& expected) {
+  string input = "VERSION: 2.0\r\n";
+  for (const auto& command : commands) input += command + "\r\n";
+  std::istringstream replies{run_session(input + "BYE\r\n")};
+  string reply;
+  std::getline(replies, reply);
+  for (size_t idx = 0; idx < commands.size(); ++idx) {
+    if (!std::getline(replies, reply)) {
+      check(false, commands[idx] + " got no reply");
+      return;
+    }
+    reply += "\n";
+    if (reply != expected[idx])
+      check(false, commands[idx] + " was answered " + reply.substr(0, 120) +
+                       ", not " + expected[idx].substr(0, 120));
+  }
+}
+
+void check_prefixes(std::mt19937_64& rng) {
+  vector<string> commands;
+  vector<string> expected;
+  const auto ask = [&](const string& prefix, const size_t limit) {
+    commands.push_back("QUERYPREFIX " + prefix +
+                       (limit ? " " + to_string(limit) : ""));
+    expected.push_back(expected_prefix(prefix, limit));
+  };
+  for (const auto& hashes : served)
+    for (size_t idx = 0; idx < 200; ++idx) {
+      const auto& hash = hashes[rng() % hashes.size()];
+      for (const size_t length : {8, 9, 10, 11, 16})
+        ask(hash.substr(0, length), rng() % 2 ? 0 : rng() % 101);
+      ask(hash, 1);
+    }
+  for (size_t idx = 0; idx < 200; ++idx)
+    ask(random_hex(rng, 8 + rng() % 8), 100);
+  // Case doesn't matter, and neither do the extremes.
+  for (auto prefix : {string("0000000"), string("FFFFFFFF")}) {
+    prefix += digits[rng() % 16];
+    ask(prefix, 100);
+  }
+  string lower = served[0][rng() % served[0].size()].substr(0, 9);
+  for (auto& ch : lower) ch = static_cast<char>(tolower(ch));
+  ask(lower, 100);
+  check_replies(commands, expected);
+}
+
+void check_nearest(std::mt19937_64& rng) {
+  fixture::nearest = true;
+  vector<string> commands;
+  vector<string> expected;
+  for (const auto& hashes : served)
+    for (size_t idx = 0; idx < 50; ++idx) {
+      const auto distance = static_cast<unsigned>(rng() % 4);
+      const auto& member = hashes[rng() % hashes.size()];
+      for (const auto& hash :
+           {member, changed(rng, member, 1 + rng() % 4),
+            random_hex(rng, member.size())}) {
+        commands.push_back("NEAREST " + hash + " " + to_string(distance));
+        expected.push_back(expected_nearest(hash, distance));
+      }
+    }
+  check_replies(commands, expected);
+  fixture::nearest = false;
+}
+
+/** The same over sets of none, one and two hashes. */
+void check_tiny_sets(std::mt19937_64& rng) {
+  fixture::nearest = true;
+  const auto first = random_hex(rng, 32);
+  const auto second = first.substr(0, 10) + random_hex(rng, 22);
+  for (const auto& hashes :
+       {vector<string>{}, vector<string>{first}, vector<string>{second},
+        vector<string>{first, second}}) {
+    served.assign(3, {});
+    served[0] = hashes;
+    std::sort(served[0].begin(), served[0].end());
+    fixture::serve(hashes);
+    vector<string> commands;
+    vector<string> expected;
+    for (const auto& probe : {first, second, changed(rng, first, 2)}) {
+      for (const size_t length : {8, 10, 11, 32}) {
+        commands.push_back("QUERYPREFIX " + probe.substr(0, length) + " 5");
+        expected.push_back(expected_prefix(probe.substr(0, length), 5));
+      }
+      for (const unsigned distance : {0, 1, 2, 3}) {
+        commands.push_back("NEAREST " + probe + " " + to_string(distance));
+        expected.push_back(expected_nearest(probe, distance));
+      }
+    }
+    check_replies(commands, expected);
+  }
+  fixture::nearest = false;
+}
+}  // namespace
+
+int main() {
+  // Seeded, so that a failure can be reproduced.
+  std::mt19937_64 rng{20190401};
+  serve_hashes(rng);
+  check_prefixes(rng);
+  check_nearest(rng);
+  check_tiny_sets(rng);
+  return failed ? EXIT_FAILURE : EXIT_SUCCESS;
+}
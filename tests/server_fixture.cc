@@ -0,0 +1,126 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#include <signal.h>
+#include <algorithm>
+#include <chrono>
+#include <cstring>
+#include <iostream>
+#include "server_fixture.h"
+
+using std::string;
+using std::vector;
+using std::chrono::steady_clock;
+
+namespace fixture {
+std::shared_ptr<const HashData> hashes{std::make_shared<HashData>()};
+std::atomic<ServerState> state{ServerState::Ready};
+bool nearest{false};
+vector<string> logged;
+
+namespace {
+template <typename T>
+void sort_and_dedup(vector<T>& hashes) {
+  std::sort(hashes.begin(), hashes.end());
+  hashes.erase(std::unique(hashes.begin(), hashes.end()), hashes.end());
+}
+}  // namespace
+
+void serve(const vector<string>& hex) {
+  HashSets sets;
+  for (const auto& hash : hex) {
+    if (40 == hash.size())
+      sets.sha1s.push_back(to_sha1(hash));
+    else if (64 == hash.size())
+      sets.sha256s.push_back(to_sha256(hash));
+    else
+      sets.md5s.push_back(to_pair64(hash));
+  }
+  sort_and_dedup(sets.md5s);
+  sort_and_dedup(sets.sha1s);
+  sort_and_dedup(sets.sha256s);
+  std::unique_ptr<BloomFilter> filter;
+  std::unique_ptr<CompactSet> compact;
+  hashes = make_hash_data(sets, filter, compact);
+}
+}  // namespace fixture
+
+namespace {
+const steady_clock::time_point start{steady_clock::now()};
+volatile sig_atomic_t stopping{0};
+std::unique_ptr<RateLimiter> limiter;
+std::unique_ptr<QueryCache> cache;
+unsigned int max_hashes{10000};
+size_t max_line{1048576};
+bool invert{false};
+char hit_mark{'1'};
+char miss_mark{'0'};
+std::atomic<unsigned int> sessions{0};
+}  // namespace
+
+const steady_clock::time_point& start_time{start};
+const volatile sig_atomic_t& stop_requested{stopping};
+const std::unique_ptr<RateLimiter>& rate_limiter{limiter};
+const std::unique_ptr<QueryCache>& query_cache{cache};
+const unsigned int& max_query_hashes{max_hashes};
+const size_t& max_line_bytes{max_line};
+const bool& invert_results{invert};
+const bool& nearest_enabled{fixture::nearest};
+const char& hit_char{hit_mark};
+const char& miss_char{miss_mark};
+const std::atomic<unsigned int>& active_sessions{sessions};
+const std::atomic<ServerState>& server_state{fixture::state};
+
+std::shared_ptr<const HashData> current_hashes() { return fixture::hashes; }
+
+std::shared_ptr<const HashData> current_hashes(const string& name) {
+  return name.empty() ? current_hashes() : nullptr;
+}
+
+vector<string> dataset_names() { return {}; }
+
+bool is_allowed_client(const boost::asio::ip::address&) { return true; }
+
+bool log_enabled(const LogLevel) { return true; }
+
+void log(const LogLevel, const string&& msg) {
+  fixture::logged.push_back(msg);
+}
+
+string StringSocketBuffer::written() const {
+  string rv;
+  for (const auto& write : sent) rv += write.second;
+  return rv;
+}
+
+long StringSocketBuffer::receive(char* buffer, const std::size_t len) {
+  const auto count = std::min({len, chunk, input.size() - offset});
+  std::memcpy(buffer, input.data() + offset, count);
+  offset += count;
+  return static_cast<long>(count);
+}
+
+bool StringSocketBuffer::transmit(const char* buffer, const std::size_t len) {
+  sent.emplace_back(offset, string(buffer, len));
+  return true;
+}
+
+string run_session(const string& input, const std::size_t chunk) {
+  StringSocketBuffer buffer(input, chunk);
+  std::iostream stream(&buffer);
+  handle_client(stream, "client");
+  return buffer.written();
+}
@@ -0,0 +1,86 @@
+/*
+Copyright (c) 2015-2019, Robert J. Hansen <rjh@sixdemonbag.org>
+
+Permission to use, copy, modify, and/or distribute this software for any
+purpose with or without fee is hereby granted, provided that the above
+copyright notice and this permission notice appear in all copies.
+
+THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+*/
+
+#ifndef SERVER_FIXTURE_H
+#define SERVER_FIXTURE_H
+
+// Stands in for main.cc, which the server's tests don't link, so that
+// they can run its sessions and endpoints without a socket.  Anything
+// it doesn't let a test change is at nsrlsvr's default.
+
+#include <atomic>
+#include <memory>
+#include <string>
+#include <utility>
+#include <vector>
+#include "main.h"
+
+namespace fixture {
+/** What current_hashes() answers from; no hashes until serve() is
+ * called.
+ */
+extern std::shared_ptr<const HashData> hashes;
+/** What server_state says; Ready unless a test says otherwise. */
+extern std::atomic<ServerState> state;
+/** Whether NEAREST is allowed, as with --nearest. */
+extern bool nearest;
+/** Everything logged, oldest first. */
+extern std::vector<std::string> logged;
+
+/** Serves the MD5s, SHA-1s and SHA-256s in hex as though they'd just
+ * been loaded.
+ */
+void serve(const std::vector<std::string>& hex);
+}  // namespace fixture
+
+/** A SocketBuffer over a string instead of a socket.  Each read hands
+ * the server at most chunk bytes of it, as the packets from a slow or
+ * chatty client might arrive.
+ */
+class StringSocketBuffer : public SocketBuffer {
+ public:
+  StringSocketBuffer(const std::string& input, const std::size_t chunk)
+      : input{input}, chunk{chunk} {}
+
+  /** Everything the server wrote. */
+  std::string written() const;
+  /** How many bytes of the input the server has read. */
+  std::size_t read() const { return offset; }
+  /** Each write the server made, with how many bytes of the input it
+   * had read by then.
+   */
+  const std::vector<std::pair<std::size_t, std::string>>& writes() const {
+    return sent;
+  }
+
+ protected:
+  long receive(char* buffer, const std::size_t len) override;
+  bool transmit(const char* buffer, const std::size_t len) override;
+
+ private:
+  const std::string input;
+  const std::size_t chunk;
+  std::size_t offset{0};
+  std::vector<std::pair<std::size_t, std::string>> sent;
+};
+
+/** Runs a whole session on input, as handle_client() would serve it to
+ * a client, and returns what the server sent back.
+ */
+std::string run_session(const std::string& input,
+                        const std::size_t chunk = 4096);
+
+#endif